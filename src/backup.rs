@@ -0,0 +1,286 @@
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use chrono::Local;
+use md5;
+use output;
+use serde_yaml;
+use shell_escape;
+use config;
+use errors::SlinkResult;
+use exec;
+use ignore::Direction;
+use paths;
+use transport::Transport;
+
+// Where snapshots go, relative to the directory being synced to
+pub const TRASH_DIR: &'static str = ".slink/trash";
+
+pub enum Error {
+    NothingToUndo,
+    FailedTrashUpdate(io::Error),
+}
+
+/*
+ * A timestamped directory on the receiving side of a sync, which everything
+ * the sync deletes or overwrites is moved into
+ */
+pub struct Snapshot {
+    name: String,
+    direction: Direction,
+    // How many snapshots to keep once this one's done
+    keep: usize,
+}
+
+// What a sync of a directory backed up and created on one side, so it can be
+// undone. A sync both syncs each way, so it can have a part for each side.
+#[derive(Serialize, Deserialize, Debug)]
+struct LastSync {
+    remote: String,
+    remote_dir: PathBuf,
+    direction: Direction,
+    snapshot: String,
+    // Relative to the directory synced to
+    #[serde(default)]
+    created: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn new(direction: Direction, keep: usize) -> Snapshot {
+        Snapshot {
+            name: Local::now().format("%Y%m%d-%H%M%S").to_string(),
+            direction: direction,
+            keep: keep,
+        }
+    }
+
+    /*
+     * Have rsync back up into the snapshot
+     */
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.arg("--backup");
+        // Relative to the destination directory
+        cmd.arg(format!("--backup-dir={}/{}", TRASH_DIR, self.name));
+    }
+
+    /*
+     * Once the sync's done, prune old snapshots and remember this one, and the
+     * paths the sync created, for sync undo
+     */
+    pub fn finish(&self, transport: &dyn Transport, remote_dir: &PathBuf, created: &[String])
+        -> SlinkResult<()>
+    {
+        finish_all(transport, remote_dir, &[(self, created)])
+    }
+
+    // rsync only makes the snapshot directory once there's something to back
+    // up in it
+    fn exists(&self, transport: &dyn Transport, remote_dir: &PathBuf) -> SlinkResult<bool> {
+        let snapshot = format!("{}/{}", TRASH_DIR, self.name);
+        match self.direction {
+            Direction::Up => {
+                let dir = remote_dir.join(snapshot);
+                let output = try!(transport.output(format!(
+                    "if test -d {}; then echo exists; fi",
+                    shell_escape::escape(Cow::Borrowed(dir.to_str().unwrap()))
+                ).as_str()));
+                Ok(output.trim() == "exists")
+            },
+            Direction::Down => Ok(paths::pwd_or_panic().join(snapshot).is_dir()),
+        }
+    }
+}
+
+/*
+ * Like Snapshot::finish, for a sync with a snapshot on each side. Syncs that
+ * neither backed up nor created anything leave the last sync that did to be
+ * undone.
+ */
+pub fn finish_all(transport: &dyn Transport, remote_dir: &PathBuf, snapshots: &[(&Snapshot, &[String])])
+    -> SlinkResult<()>
+{
+    let mut parts = Vec::new();
+    for &(snapshot, created) in snapshots {
+        if !try!(snapshot.exists(transport, remote_dir)) && created.is_empty() {
+            continue;
+        }
+
+        match snapshot.direction {
+            Direction::Up => try!(prune_remote(transport, remote_dir, snapshot.keep)),
+            Direction::Down => try!(prune_local(snapshot.keep)),
+        }
+
+        parts.push(LastSync {
+            remote: transport.name().to_string(),
+            remote_dir: remote_dir.clone(),
+            direction: snapshot.direction,
+            snapshot: snapshot.name.clone(),
+            created: created.to_vec(),
+        });
+    }
+
+    if parts.is_empty() {
+        return Ok(());
+    }
+
+    let file = try!(File::create(last_sync_file()).map_err(|e| {
+        config::Error::FailedConfigWrite(e)
+    }));
+    try!(serde_yaml::to_writer(file, &parts).map_err(|e| {
+        config::Error::MalformedConfig(e)
+    }));
+
+    Ok(())
+}
+
+/*
+ * Undo the last sync of the PWD that backed anything up or created anything:
+ * remove what it created, and put back everything it deleted or overwrote
+ */
+pub fn undo(transport: &dyn Transport) -> SlinkResult<()> {
+    let path = last_sync_file();
+    let parts: Vec<LastSync> = match File::open(&path) {
+        Err(_) => return Err(Error::NothingToUndo.into()),
+        Ok(file) => try!(serde_yaml::from_reader(file).map_err(|e| {
+            config::Error::MalformedConfig(e)
+        })),
+    };
+
+    // Undoing on a different remote would put the wrong files back
+    if parts.is_empty() || parts.iter().any(|part| part.remote != transport.name()) {
+        return Err(Error::NothingToUndo.into());
+    }
+
+    let mut undone = false;
+    for part in parts.iter() {
+        undone |= try!(undo_part(transport, part));
+    }
+
+    // Snapshots that were pruned or removed since can't be put back, so the
+    // sync can't be undone any more
+    let _ = fs::remove_file(path);
+    if !undone {
+        return Err(Error::NothingToUndo.into());
+    }
+
+    output::info(&format!("Undid the sync at {}", parts[0].snapshot));
+    Ok(())
+}
+
+// Undo one side of a sync, returning whether there was anything to undo
+fn undo_part(transport: &dyn Transport, part: &LastSync) -> SlinkResult<bool> {
+    let snapshot = format!("{}/{}", TRASH_DIR, part.snapshot);
+
+    match part.direction {
+        Direction::Up => {
+            let escaped = shell_escape::escape(Cow::Borrowed(snapshot.as_str()));
+            let mut steps = String::new();
+            if !part.created.is_empty() {
+                steps.push_str(&exec::shell_join("rm -rf --", &part.created));
+                steps.push_str(" && echo removed; ");
+            }
+            steps.push_str(&format!(
+                "if test -d {}; then cp -a {}/. . && rm -rf {} && echo restored; fi;",
+                escaped,
+                escaped,
+                escaped
+            ));
+            let command = format!(
+                "cd {} && {{ {} }}",
+                shell_escape::escape(Cow::Borrowed(part.remote_dir.to_str().unwrap())),
+                steps
+            );
+
+            let output = try!(transport.output(command.as_str()));
+            Ok(output.trim() != "")
+        },
+        Direction::Down => {
+            let pwd = paths::pwd_or_panic();
+            for created in part.created.iter() {
+                try!(remove(pwd.join(created).as_path()).map_err(Error::FailedTrashUpdate));
+            }
+
+            let snapshot_dir = pwd.join(&snapshot);
+            if !snapshot_dir.is_dir() {
+                return Ok(!part.created.is_empty());
+            }
+            try!(restore(snapshot_dir.as_path(), pwd.as_path()).map_err(Error::FailedTrashUpdate));
+            try!(fs::remove_dir_all(snapshot_dir).map_err(Error::FailedTrashUpdate));
+            Ok(true)
+        },
+    }
+}
+
+// Remove a file or directory a sync created, unless it's gone already
+fn remove(path: &Path) -> io::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Err(_) => return Ok(()),
+        Ok(ref metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+    };
+
+    match result {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+// Move everything in a snapshot back where it came from
+fn restore(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in try!(fs::read_dir(from)) {
+        let entry = try!(entry);
+        let target = to.join(entry.file_name());
+
+        if try!(entry.file_type()).is_dir() && target.is_dir() {
+            try!(restore(entry.path().as_path(), target.as_path()));
+        } else {
+            if target.is_dir() {
+                try!(fs::remove_dir_all(&target));
+            }
+            try!(fs::rename(entry.path(), &target));
+        }
+    }
+
+    Ok(())
+}
+
+// Snapshot names sort by time, so keep the last ones
+fn prune_local(keep: usize) -> SlinkResult<()> {
+    let trash = paths::pwd_or_panic().join(TRASH_DIR);
+    let mut snapshots: Vec<PathBuf> = match fs::read_dir(&trash) {
+        Err(_) => return Ok(()),
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+    };
+    snapshots.sort();
+
+    let old = snapshots.len().saturating_sub(keep);
+    for snapshot in snapshots.into_iter().take(old) {
+        try!(fs::remove_dir_all(snapshot).map_err(Error::FailedTrashUpdate));
+    }
+
+    Ok(())
+}
+
+fn prune_remote(transport: &dyn Transport, remote_dir: &PathBuf, keep: usize) -> SlinkResult<()> {
+    let trash = remote_dir.join(TRASH_DIR);
+    let trash = trash.to_str().unwrap();
+
+    try!(transport.output(format!(
+        "if cd {} 2>/dev/null; then ls -1 | sort -r | tail -n +{} | \
+         while IFS= read -r name; do rm -rf -- \"$name\"; done; fi",
+        shell_escape::escape(Cow::Borrowed(trash)),
+        keep + 1
+    ).as_str()));
+
+    Ok(())
+}
+
+fn last_sync_file() -> PathBuf {
+    let pwd = paths::pwd_or_panic();
+    let name = format!("backups/{:x}.yml", md5::compute(pwd.to_str().unwrap().as_bytes()));
+
+    let dirs = config::xdg_dirs().unwrap();
+    dirs.place_data_file(name).expect("Could not create backup state directory")
+}
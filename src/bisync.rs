@@ -0,0 +1,491 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local};
+use md5;
+use output;
+use serde_yaml;
+use shell_escape;
+use backup::{self, TRASH_DIR};
+use config::{self, ConflictPolicy};
+use errors::SlinkResult;
+use exec;
+use ignore::Direction;
+use paths;
+use rsync::{self, Signature, MTIME_FORMAT};
+use transport::Transport;
+
+pub enum Error {
+    // Paths that changed on both sides were left alone, since there was no
+    // policy to resolve them
+    Unresolved(usize),
+    // One side has nothing left of what was last synced, while the other
+    // still has files, which looks more like a missing directory than
+    // deletions worth copying. Holds the empty side and the state file.
+    Emptied(&'static str, PathBuf),
+    // The remote has neither md5sum nor BSD's md5 to compare files with
+    NoMd5,
+}
+
+// What a file looked like the last time both sides had the same copy of it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Synced {
+    size: u64,
+    mtime: String,
+    #[serde(default)]
+    hash: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct State {
+    files: BTreeMap<PathBuf, Synced>,
+}
+
+// What a sync did, for --json output
+#[derive(Serialize)]
+struct Summary<'a> {
+    sent: &'a BTreeSet<PathBuf>,
+    fetched: &'a BTreeSet<PathBuf>,
+    conflicts: Vec<Conflict<'a>>,
+}
+
+#[derive(Serialize)]
+struct Conflict<'a> {
+    path: &'a PathBuf,
+    resolution: &'static str,
+}
+
+/*
+ * Sync the PWD and a directory on the remote both ways: changes made on only
+ * one side since the last sync are copied to the other, and paths changed on
+ * both are resolved with the policy, or left alone without one.
+ */
+pub fn sync(transport: &dyn Transport, remote_dir: PathBuf, policy: Option<ConflictPolicy>)
+    -> SlinkResult<()>
+{
+    let pwd = paths::pwd_or_panic();
+    let state_path = state_file(transport, &pwd, &remote_dir);
+    let last = try!(read_state(&state_path));
+
+    let settings = try!(config::project_config()).sync;
+    let mut ignores = config::ignores(&settings, Direction::Up);
+
+    // Snapshots stay on the side they were made on
+    let mut local = BTreeMap::new();
+    for entry in ignores.files(pwd.as_path()) {
+        if entry.path.starts_with(TRASH_DIR) {
+            continue;
+        }
+        if let Some(signature) = local_signature(&pwd.join(&entry.path)) {
+            local.insert(entry.path, signature);
+        }
+    }
+
+    let mut remote = BTreeMap::new();
+    for (entry, signature) in try!(rsync::list_signed(transport, &remote_dir)) {
+        if !entry.is_dir && !entry.path.starts_with(TRASH_DIR) &&
+            !ignores.is_ignored(pwd.as_path(), &entry.path, false) {
+            remote.insert(entry.path, signature);
+        }
+    }
+
+    try!(check_emptied(&last, &local, &remote).map_err(|side| {
+        Error::Emptied(side, state_path.clone())
+    }));
+
+    let mut paths: BTreeSet<PathBuf> = local.keys().cloned().collect();
+    paths.extend(remote.keys().cloned());
+    paths.extend(last.files.keys().cloned());
+
+    let mut push = BTreeSet::new();
+    let mut pull = BTreeSet::new();
+    let mut candidates = Vec::new();
+
+    for path in paths.iter() {
+        let local_signature = local.get(path);
+        let remote_signature = remote.get(path);
+        if local_signature == remote_signature {
+            continue;
+        }
+
+        let synced = last.files.get(path);
+        let local_changed = changed(local_signature, synced) &&
+            !same_content(synced, || local_signature.and_then(|_| hash_file(&pwd.join(path))));
+        let remote_changed = changed(remote_signature, synced);
+
+        if local_changed && remote_changed {
+            candidates.push(path.clone());
+        } else if remote_changed {
+            pull.insert(path.clone());
+        } else {
+            // Includes a local file that was touched without changing, whose
+            // new time the remote should pick up
+            push.insert(path.clone());
+        }
+    }
+
+    // Paths changed on both sides might still have ended up the same, or only
+    // had their times changed on one of them
+    let both_exist: Vec<PathBuf> = candidates.iter().filter(|path| {
+        local.contains_key(*path) && remote.contains_key(*path)
+    }).cloned().collect();
+    let remote_hashes = try!(hash_remote(transport, &remote_dir, &both_exist));
+
+    let mut conflicts = Vec::new();
+    for path in candidates {
+        let remote_hash = remote_hashes.get(&path).cloned();
+        let local_hash = if remote_hash.is_some() { hash_file(&pwd.join(&path)) } else { None };
+
+        if remote_hash.is_some() && remote_hash == local_hash {
+            push.insert(path);
+        } else if same_content(last.files.get(&path), || remote_hash.clone()) {
+            push.insert(path);
+        } else {
+            conflicts.push(path);
+        }
+    }
+
+    let mut unresolved = Vec::new();
+    for path in conflicts.iter() {
+        match policy {
+            Some(ConflictPolicy::LocalWins) => { push.insert(path.clone()); },
+            Some(ConflictPolicy::RemoteWins) => { pull.insert(path.clone()); },
+            Some(ConflictPolicy::KeepBoth) => {
+                if !local.contains_key(path) {
+                    pull.insert(path.clone());
+                } else if !remote.contains_key(path) {
+                    push.insert(path.clone());
+                } else {
+                    let copy = try!(keep_remote_copy(transport, &remote_dir, path));
+                    push.insert(path.clone());
+                    push.insert(copy);
+                }
+            },
+            None => unresolved.push(path.clone()),
+        }
+    }
+
+    // Each side backs up what it loses into a snapshot of its own
+    let send_backup = rsync::snapshot(&settings, Direction::Up);
+    let fetch_backup = rsync::snapshot(&settings, Direction::Down);
+    if !push.is_empty() {
+        try!(rsync::send(transport, &remote_dir, &push, send_backup.as_ref()));
+    }
+    if !pull.is_empty() {
+        try!(rsync::fetch(transport, &remote_dir, &pull, fetch_backup.as_ref()));
+    }
+
+    // Whatever's copied to the side that didn't have it is created there
+    let sent: Vec<String> = push.iter()
+        .filter(|path| !remote.contains_key(*path) && pwd.join(path).exists())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    let fetched: Vec<String> = pull.iter()
+        .filter(|path| !local.contains_key(*path) && remote.contains_key(*path))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    let mut snapshots = Vec::new();
+    if let Some(ref snapshot) = send_backup {
+        snapshots.push((snapshot, &sent[..]));
+    }
+    if let Some(ref snapshot) = fetch_backup {
+        snapshots.push((snapshot, &fetched[..]));
+    }
+    try!(backup::finish_all(transport, &remote_dir, &snapshots));
+
+    paths.extend(push.iter().cloned());
+    let transferred: BTreeSet<&PathBuf> = push.iter().chain(pull.iter()).collect();
+    let mut state = State::default();
+    for path in paths.iter() {
+        // Unresolved paths keep their old state, so they're still conflicts
+        // next time
+        if unresolved.contains(path) {
+            if let Some(synced) = last.files.get(path) {
+                state.files.insert(path.clone(), synced.clone());
+            }
+            continue;
+        }
+
+        let signature = match local_signature(&pwd.join(path)) {
+            Some(signature) => signature,
+            None => continue,
+        };
+
+        // Keep the old hash while the file is unchanged, and hash anything
+        // that was just copied
+        let hash = if transferred.contains(path) {
+            hash_file(&pwd.join(path))
+        } else {
+            last.files.get(path).and_then(|synced| {
+                if synced.size == signature.size && synced.mtime == signature.mtime {
+                    synced.hash.clone()
+                } else {
+                    None
+                }
+            })
+        };
+
+        state.files.insert(path.clone(), Synced {
+            size: signature.size,
+            mtime: signature.mtime,
+            hash: hash,
+        });
+    }
+    try!(write_state(&state_path, &state));
+
+    let resolution = match policy {
+        Some(ConflictPolicy::LocalWins) => "local copy kept",
+        Some(ConflictPolicy::RemoteWins) => "remote copy kept",
+        Some(ConflictPolicy::KeepBoth) => "both copies kept",
+        None => "left alone",
+    };
+
+    if output::json() {
+        output::print_json(&Summary {
+            sent: &push,
+            fetched: &pull,
+            conflicts: conflicts.iter().map(|path| {
+                Conflict { path: path, resolution: resolution }
+            }).collect(),
+        });
+    } else {
+        println!(
+            "{} sent, {} fetched, {} conflict{}",
+            push.len(),
+            pull.len(),
+            conflicts.len(),
+            if conflicts.len() == 1 { "" } else { "s" }
+        );
+        for path in conflicts.iter() {
+            println!("  conflict  {} ({})", path.display(), resolution);
+        }
+    }
+
+    if unresolved.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Unresolved(unresolved.len()).into())
+    }
+}
+
+// Whether one side's copy differs from the last synced one, going by signature
+fn changed(signature: Option<&Signature>, synced: Option<&Synced>) -> bool {
+    match (signature, synced) {
+        (None, None) => false,
+        (Some(signature), Some(synced)) => {
+            signature.size != synced.size || signature.mtime != synced.mtime
+        },
+        _ => true,
+    }
+}
+
+// Whether a copy whose signature changed still has the last synced content.
+// Hashing is put off until there's a hash to compare against.
+fn same_content<F>(synced: Option<&Synced>, hash: F) -> bool
+    where F: FnOnce() -> Option<String>
+{
+    match synced.and_then(|synced| synced.hash.clone()) {
+        None => false,
+        Some(synced_hash) => hash() == Some(synced_hash),
+    }
+}
+
+// Fetch the remote's copy of a conflicting path alongside the local one, with
+// a suffix. Returns the copy's path.
+fn keep_remote_copy(transport: &dyn Transport, remote_dir: &PathBuf, path: &PathBuf)
+    -> SlinkResult<PathBuf>
+{
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(format!(".remote-{}", Local::now().format("%Y%m%d-%H%M%S")));
+    let copy = path.with_file_name(name);
+
+    let pwd = paths::pwd_or_panic();
+    try!(transport.download(remote_dir.join(path).as_path(), pwd.join(&copy).as_path()));
+    Ok(copy)
+}
+
+// Which side, if either, has nothing on it while the other does, since the
+// last sync left files on both
+fn check_emptied(last: &State, local: &BTreeMap<PathBuf, Signature>, remote: &BTreeMap<PathBuf, Signature>)
+    -> Result<(), &'static str>
+{
+    if last.files.is_empty() {
+        return Ok(());
+    }
+
+    match (local.is_empty(), remote.is_empty()) {
+        (true, false) => Err("local"),
+        (false, true) => Err("remote"),
+        _ => Ok(()),
+    }
+}
+
+// The signature of a local file, in the same form rsync lists the remote's
+fn local_signature(path: &Path) -> Option<Signature> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return None,
+    };
+    let modified: DateTime<Local> = match metadata.modified() {
+        Ok(modified) => modified.into(),
+        Err(_) => return None,
+    };
+
+    Some(Signature {
+        size: metadata.len(),
+        mtime: modified.format(MTIME_FORMAT).to_string(),
+    })
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+
+    let mut context = md5::Context::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => context.consume(&buffer[..len]),
+            Err(_) => return None,
+        }
+    }
+
+    Some(format!("{:x}", context.compute()))
+}
+
+// Hash files in a directory on the remote, so they can be compared with local
+// copies. That's with md5sum where there is one, or md5 on BSDs and macOS; the
+// first line of the output says which, since they print differently.
+fn hash_remote(transport: &dyn Transport, remote_dir: &PathBuf, paths: &[PathBuf])
+    -> SlinkResult<BTreeMap<PathBuf, String>>
+{
+    let mut hashes = BTreeMap::new();
+    if paths.is_empty() {
+        return Ok(hashes);
+    }
+
+    let mut args = vec![String::from("--")];
+    args.extend(paths.iter().map(|path| path.to_str().unwrap().to_string()));
+    let dir = remote_dir.to_str().unwrap();
+    let command = format!(
+        "cd {} && if command -v md5sum >/dev/null 2>&1; then echo md5sum && {}; \
+         elif command -v md5 >/dev/null 2>&1; then echo md5 && {}; fi",
+        shell_escape::escape(Cow::Borrowed(dir)),
+        exec::shell_join("md5sum", &args),
+        exec::shell_join("md5 -r", &args)
+    );
+
+    let output = try!(transport.output(command.as_str()));
+    let mut lines = output.lines();
+    // md5sum prints "<hash>  <path>", with a leading backslash for names it
+    // had to escape, which just go unmatched; md5 -r prints "<hash> <path>"
+    let separator = match lines.next() {
+        Some("md5sum") => "  ",
+        Some("md5") => " ",
+        _ => return Err(Error::NoMd5.into()),
+    };
+
+    for line in lines {
+        let mut parts = line.splitn(2, separator);
+        if let (Some(hash), Some(path)) = (parts.next(), parts.next()) {
+            hashes.insert(PathBuf::from(path), hash.to_string());
+        }
+    }
+
+    Ok(hashes)
+}
+
+// Each local directory has separate state for each remote directory it's
+// synced with
+fn state_file(transport: &dyn Transport, pwd: &PathBuf, remote_dir: &PathBuf) -> PathBuf {
+    let key = format!("{}\n{}\n{}", pwd.display(), transport.name(), remote_dir.display());
+    let name = format!("sync/{:x}.yml", md5::compute(key.as_bytes()));
+
+    let dirs = config::xdg_dirs().unwrap();
+    dirs.place_data_file(name).expect("Could not create sync state directory")
+}
+
+fn read_state(path: &PathBuf) -> SlinkResult<State> {
+    let mut file = match File::open(path) {
+        Err(_) => return Ok(State::default()),
+        Ok(file) => file,
+    };
+
+    let mut contents = String::new();
+    try!(file.read_to_string(&mut contents).map_err(|e| {
+        config::Error::FailedConfigRead(e)
+    }));
+
+    let state = try!(serde_yaml::from_str(contents.as_str()).map_err(|e| {
+        config::Error::MalformedConfig(e)
+    }));
+    Ok(state)
+}
+
+fn write_state(path: &PathBuf, state: &State) -> SlinkResult<()> {
+    let file = try!(File::create(path).map_err(|e| {
+        config::Error::FailedConfigWrite(e)
+    }));
+
+    try!(serde_yaml::to_writer(file, state).map_err(|e| {
+        config::Error::MalformedConfig(e)
+    }));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use rsync::Signature;
+    use super::{check_emptied, State, Synced};
+
+    fn files(paths: &[&str]) -> BTreeMap<PathBuf, Signature> {
+        paths.iter().map(|path| {
+            (PathBuf::from(path), Signature { size: 1, mtime: String::from("2018/01/01 12:00:00") })
+        }).collect()
+    }
+
+    fn state(paths: &[&str]) -> State {
+        let mut state = State::default();
+        for path in paths {
+            state.files.insert(PathBuf::from(path), Synced {
+                size: 1,
+                mtime: String::from("2018/01/01 12:00:00"),
+                hash: None,
+            });
+        }
+        state
+    }
+
+    #[test]
+    fn empty_remote_after_sync_is_refused() {
+        let last = state(&["a", "b"]);
+        assert_eq!(check_emptied(&last, &files(&["a", "b"]), &files(&[])), Err("remote"));
+    }
+
+    #[test]
+    fn empty_local_after_sync_is_refused() {
+        let last = state(&["a", "b"]);
+        assert_eq!(check_emptied(&last, &files(&[]), &files(&["a"])), Err("local"));
+    }
+
+    #[test]
+    fn first_sync_may_start_from_an_empty_side() {
+        assert_eq!(check_emptied(&state(&[]), &files(&["a"]), &files(&[])), Ok(()));
+        assert_eq!(check_emptied(&state(&[]), &files(&[]), &files(&["a"])), Ok(()));
+    }
+
+    #[test]
+    fn partial_deletions_are_allowed() {
+        let last = state(&["a", "b"]);
+        assert_eq!(check_emptied(&last, &files(&["a"]), &files(&["b"])), Ok(()));
+        assert_eq!(check_emptied(&last, &files(&[]), &files(&[])), Ok(()));
+    }
+}
@@ -1,15 +1,35 @@
 use std::path::PathBuf;
 use std::vec::Vec;
+use config::ConflictPolicy;
+use forward::Forward;
+use relay::Relay;
+use remote;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "slink", about = "Interact with remote machines over SSH")]
+pub struct Slink {
+    #[structopt(long = "json", raw(global = "true"),
+                help = "Print output and errors as JSON, for scripts")]
+    pub json: bool,
+
+    #[structopt(subcommand)]
+    pub command: SlinkCommand,
+}
+
+#[derive(StructOpt, Debug)]
 pub enum SlinkCommand {
     #[structopt(name = "use", about = "Update which remote machine slink uses")]
     Use {
-        #[structopt(help = "The hostname of the remote machine")]
+        #[structopt(help = "The name of a configured remote, or a hostname")]
         host: String,
     },
 
+    #[structopt(name = "remote", about = "Manage named remote machines")]
+    Remote {
+        #[structopt(subcommand)]
+        command: RemoteCommand,
+    },
+
     #[structopt(name = "go", about = "SSH to the remote")]
     Go,
 
@@ -29,32 +49,293 @@ pub enum SlinkCommand {
     Upload {
         #[structopt(help = "Path to local file", parse(from_os_str))]
         path: PathBuf,
+
+        #[structopt(help = "Path to upload to, instead of the file's mirror on the remote",
+                    parse(from_os_str))]
+        remote_path: Option<PathBuf>,
     },
 
     #[structopt(name = "download", about = "Download a file from the remote")]
     Download {
-        #[structopt(help = "Path to remote file", parse(from_os_str))]
+        #[structopt(help = "Path to remote file, and where to download it locally",
+                    parse(from_os_str))]
         path: PathBuf,
+
+        #[structopt(help = "Path to download from, instead of the file's mirror on the remote",
+                    parse(from_os_str))]
+        remote_path: Option<PathBuf>,
     },
 
-    #[structopt(name = "current", about = "Print current remote hostname")]
+    #[structopt(name = "current", about = "Print current remote")]
     Current,
 
-    #[structopt(name = "forward", about = "Forward ports")]
+    #[structopt(name = "status", about = "Check the current remote's connection")]
+    Status,
+
+    #[structopt(name = "connect", about = "Open a shared connection to the remote ahead of time")]
+    Connect {
+        #[structopt(long = "hold", help = "Keep the connection open until slink disconnect")]
+        hold: bool,
+    },
+
+    #[structopt(name = "disconnect", about = "Close the shared connection to the remote")]
+    Disconnect,
+
+    #[structopt(name = "reset", about = "Switch back to the previously-used remote")]
+    Reset,
+
+    #[structopt(name = "history", about = "List previously-used remotes, most recent first")]
+    History,
+
+    #[structopt(name = "clear", about = "Delete all remote configuration and close connections")]
+    Clear,
+
+    #[structopt(name = "forward", about = "Forward ports",
+                raw(setting = "::structopt::clap::AppSettings::SubcommandsNegateReqs"))]
     Forward {
-        #[structopt(name = "PORTS", help = "Ports to forward")]
-        ports: Vec<String>,
+        #[structopt(short = "d", long = "detach",
+                    help = "Forward in the background, over the shared connection")]
+        detach: bool,
+
+        #[structopt(name = "PORTS",
+                    help = "Local ports to forward to the remote: port, local:remote or local:host:remote",
+                    parse(try_from_str = "Forward::local"),
+                    raw(required_unless_one = r#"&["reverse", "socks"]"#))]
+        ports: Vec<Forward>,
+
+        #[structopt(short = "R", long = "reverse",
+                    help = "Remote ports to forward back here: port, remote:local or remote:host:local",
+                    parse(try_from_str = "Forward::remote"), raw(number_of_values = "1"))]
+        reverse: Vec<Forward>,
+
+        #[structopt(short = "D", long = "socks",
+                    help = "Local port for a SOCKS proxy that connects from the remote",
+                    parse(try_from_str = "Forward::dynamic"), raw(number_of_values = "1"))]
+        socks: Vec<Forward>,
+
+        #[structopt(subcommand)]
+        command: Option<ForwardCommand>,
     },
 
     #[structopt(name = "debug", about = "Print various debug messages")]
     Debug,
+
+    // Used by rsync as its remote shell for transports other than ssh
+    #[structopt(name = "rsh", raw(setting = "::structopt::clap::AppSettings::Hidden",
+                                  setting = "::structopt::clap::AppSettings::TrailingVarArg"))]
+    Rsh {
+        remote: String,
+
+        #[structopt(raw(allow_hyphen_values = "true"))]
+        command: Vec<String>,
+    },
+
+    // Used by ssh as its ProxyCommand for remotes reached via another remote
+    #[structopt(name = "proxy", raw(setting = "::structopt::clap::AppSettings::Hidden"))]
+    Proxy {
+        via: String,
+        host: String,
+        port: u16,
+    },
+
+    // Run with sudo to listen on privileged ports, relaying connections to the
+    // unprivileged ports ssh forwards them from
+    #[structopt(name = "relay", raw(setting = "::structopt::clap::AppSettings::Hidden"))]
+    Relay {
+        #[structopt(long = "background")]
+        background: bool,
+
+        #[structopt(parse(try_from_str = "Relay::parse"), raw(required = "true"))]
+        relays: Vec<Relay>,
+    },
 }
 
 #[derive(StructOpt, Debug)]
 pub enum RsyncDirection {
     #[structopt(name = "up", about = "Sync directory up to the remote machine")]
-    Up,
+    Up {
+        #[structopt(short = "w", long = "watch", help = "Keep syncing changes until interrupted")]
+        watch: bool,
+
+        #[structopt(short = "n", long = "dry-run", conflicts_with = "watch",
+                    help = "Show what would be created, updated and deleted, without syncing")]
+        dry_run: bool,
+
+        #[structopt(long = "confirm", help = "Ask before syncing if it would delete too many files")]
+        confirm: bool,
+
+        #[structopt(short = "q", long = "quiet", conflicts_with = "watch",
+                    help = "Only print errors")]
+        quiet: bool,
+
+        #[structopt(short = "v", long = "verbose", raw(conflicts_with_all = r#"&["watch", "quiet"]"#),
+                    help = "Print each file created, updated and deleted")]
+        verbose: bool,
+
+        #[structopt(help = "Remote directory to sync to, instead of the mirror of this one",
+                    parse(from_os_str))]
+        remote_path: Option<PathBuf>,
+    },
 
     #[structopt(name = "down", about = "Sync directory down from the remote machine")]
-    Down,
+    Down {
+        #[structopt(short = "n", long = "dry-run",
+                    help = "Show what would be created, updated and deleted, without syncing")]
+        dry_run: bool,
+
+        #[structopt(long = "confirm", help = "Ask before syncing if it would delete too many files")]
+        confirm: bool,
+
+        #[structopt(short = "q", long = "quiet", help = "Only print errors")]
+        quiet: bool,
+
+        #[structopt(short = "v", long = "verbose", conflicts_with = "quiet",
+                    help = "Print each file created, updated and deleted")]
+        verbose: bool,
+
+        #[structopt(help = "Remote directory to sync from, instead of the mirror of this one",
+                    parse(from_os_str))]
+        remote_path: Option<PathBuf>,
+    },
+
+    #[structopt(name = "undo",
+                about = "Restore what the last backed-up sync deleted or overwrote")]
+    Undo,
+
+    #[structopt(name = "both", about = "Sync changes both ways, detecting conflicts")]
+    Both {
+        #[structopt(long = "conflicts",
+                    help = "Resolve conflicts with local-wins, remote-wins or keep-both")]
+        conflicts: Option<ConflictPolicy>,
+
+        #[structopt(help = "Remote directory to sync with, instead of the mirror of this one",
+                    parse(from_os_str))]
+        remote_path: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum ForwardCommand {
+    #[structopt(name = "list", about = "List the ports forwarded in the background")]
+    List,
+
+    #[structopt(name = "stop", about = "Stop forwarding ports in the background")]
+    Stop {
+        #[structopt(name = "PORTS", help = "Ports the forwards listen on", raw(required = "true"))]
+        ports: Vec<u16>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum RemoteCommand {
+    #[structopt(name = "add", about = "Add a named remote machine")]
+    Add {
+        #[structopt(subcommand)]
+        remote: AddRemote,
+    },
+
+    #[structopt(name = "remove", about = "Remove a named remote machine")]
+    Remove {
+        #[structopt(help = "Shortcut name for the remote")]
+        name: String,
+    },
+
+    #[structopt(name = "list", about = "List the configured remote machines")]
+    List,
+
+    #[structopt(name = "rename", about = "Rename a remote machine")]
+    Rename {
+        #[structopt(help = "Current shortcut name for the remote")]
+        old: String,
+
+        #[structopt(help = "New shortcut name for the remote")]
+        new: String,
+    },
+
+    #[structopt(name = "use", about = "Update which named remote slink uses")]
+    Use {
+        #[structopt(help = "Shortcut name for the remote")]
+        name: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum AddRemote {
+    #[structopt(name = "ssh", about = "Add a remote reached over SSH")]
+    Ssh {
+        #[structopt(help = "Shortcut name for the remote")]
+        name: String,
+
+        #[structopt(help = "The hostname of the remote machine")]
+        hostname: String,
+
+        #[structopt(short = "u", long = "user", help = "User to log in as")]
+        user: Option<String>,
+
+        #[structopt(short = "p", long = "port", help = "SSH port on the remote machine")]
+        port: Option<u16>,
+
+        #[structopt(short = "i", long = "identity", help = "Identity file to authenticate with",
+                    parse(from_os_str))]
+        identity_file: Option<PathBuf>,
+
+        #[structopt(long = "persist",
+                    help = "How long to keep the shared connection open after its last use, e.g. 30m",
+                    parse(try_from_str = "remote::parse_persist"))]
+        persist: Option<String>,
+    },
+
+    #[structopt(name = "kubectl", about = "Add a remote pod reached with kubectl")]
+    Kubectl {
+        #[structopt(help = "Shortcut name for the remote")]
+        name: String,
+
+        #[structopt(long = "pod", help = "Name of the pod",
+                    raw(required_unless = r#""selector""#, conflicts_with = r#""selector""#))]
+        pod: Option<String>,
+
+        #[structopt(short = "l", long = "selector",
+                    help = "Label selector; the first running pod matching it is used")]
+        selector: Option<String>,
+
+        #[structopt(short = "n", long = "namespace", help = "Namespace of the pod")]
+        namespace: Option<String>,
+
+        #[structopt(short = "c", long = "container", help = "Container within the pod")]
+        container: Option<String>,
+
+        #[structopt(long = "context", help = "kubeconfig context to use")]
+        context: Option<String>,
+
+        #[structopt(long = "via", help = "Run kubectl on another remote")]
+        via: Option<String>,
+    },
+
+    #[structopt(name = "forward", about = "Add a remote reached over SSH through another remote")]
+    Forward {
+        #[structopt(help = "Shortcut name for the remote")]
+        name: String,
+
+        #[structopt(long = "via", help = "The remote to connect through")]
+        via: String,
+
+        #[structopt(long = "port", help = "SSH port, as seen from the via remote")]
+        port: u16,
+
+        #[structopt(long = "host", help = "SSH host, as seen from the via remote",
+                    default_value = "localhost")]
+        hostname: String,
+
+        #[structopt(short = "u", long = "user", help = "User to log in as")]
+        user: Option<String>,
+
+        #[structopt(short = "i", long = "identity", help = "Identity file to authenticate with",
+                    parse(from_os_str))]
+        identity_file: Option<PathBuf>,
+
+        #[structopt(long = "persist",
+                    help = "How long to keep the shared connection open after its last use, e.g. 30m",
+                    parse(try_from_str = "remote::parse_persist"))]
+        persist: Option<String>,
+    },
 }
@@ -1,61 +1,82 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::env;
 use std::io;
 use std::fs::File;
-use std::io::Write;
 use std::io::Read;
-use std::collections::HashSet;
+use std::str::FromStr;
 use errors::SlinkResult;
-use paths::relative_pwd;
+use forward::Forward;
+use ignore::{Direction, Ignores};
+use paths::{self, relative_pwd};
+use process;
+use remote::{Remote, Remotes};
 
+use serde_yaml;
 use xdg;
 
-const HOST_CONFIG_FILE: &'static str = "hostname";
-
 pub enum Error {
     NoConfigFile,
     FailedConfigWrite(io::Error),
     FailedConfigRead(io::Error),
+    MalformedConfig(serde_yaml::Error),
+    NoSuchRemote(String),
+    RemoteExists(String),
+    // Removing the remote would strand the remotes reached through it
+    RemoteInUse(String, Vec<String>),
+    InvalidForward(String),
 }
 
 /*
- * Set the host used for SSH connections.
+ * Set the remote used for connections. If no remote with the given name has
+ * been configured, one is added using the name as its hostname.
  */
 pub fn set_host(host: &str) -> SlinkResult<()> {
-    let dirs = xdg_dirs().unwrap();
-    let host_path = dirs.place_config_file(HOST_CONFIG_FILE)
-                        .expect("Cannot create config file");
+    let mut remotes = try!(Remotes::load());
 
-    let mut file = try!(File::create(host_path).map_err(|e| {
-        Error::FailedConfigWrite(e)
-    }));
-
-    try!(file.write(format!("{}\n", host).as_bytes()).map_err(|e| {
-        Error::FailedConfigWrite(e)
-    }));
+    if !remotes.contains(host) {
+        try!(remotes.add(Remote::new(host, host)));
+    }
+    try!(remotes.set_current(host));
 
-    Ok(())
+    remotes.save()
 }
 
 /*
- * Get the host used for SSH connections.
+ * Commands that .slink/config can point at a different remote than the rest
  */
-pub fn get_host() -> SlinkResult<String> {
-    let dirs = xdg_dirs().unwrap();
-    let path = try!(
-        dirs.find_config_file(HOST_CONFIG_FILE).ok_or(Error::NoConfigFile)
-    );
-
-    let mut file = try!(File::open(path).map_err(|e| {
-        Error::FailedConfigRead(e)
-    }));
+#[derive(Clone, Copy, Debug)]
+pub enum CommandKind {
+    Go,
+    Run,
+    Sync,
+    Upload,
+    Download,
+    Forward,
+}
 
-    let mut host = String::new();
-    try!(file.read_to_string(&mut host).map_err(|e| {
-        Error::FailedConfigRead(e)
-    }));
+/*
+ * Get the remote used for connections: the one pinned by the nearest
+ * .slink/config, or the global default otherwise.
+ */
+pub fn get_remote() -> SlinkResult<Remote> {
+    let remotes = try!(Remotes::load());
+    match try!(project_config()).remote {
+        Some(name) => remotes.get(name.as_str()),
+        None => remotes.current(),
+    }
+}
 
-    Ok(host.trim().to_string())
+/*
+ * Get the remote a particular command should connect to, which may be
+ * overridden separately from the remote everything else uses.
+ */
+pub fn get_remote_for(command: CommandKind) -> SlinkResult<Remote> {
+    let remotes = try!(Remotes::load());
+    let config = try!(project_config());
+    match config.commands.get(command).or(config.remote) {
+        Some(name) => remotes.get(name.as_str()),
+        None => remotes.current(),
+    }
 }
 
 // Returns the XDG base dirs for slink
@@ -63,63 +84,375 @@ pub fn xdg_dirs() -> Result<xdg::BaseDirectories, xdg::BaseDirectoriesError> {
     xdg::BaseDirectories::with_prefix("slink")
 }
 
-pub fn ignored_files() -> HashSet<String> {
+/*
+ * Get the ignore patterns that apply to the PWD: the global ignore files, then
+ * those in each directory from just below $HOME down to the PWD. Files inside
+ * the PWD are picked up when it's walked.
+ */
+pub fn ignores(settings: &SyncConfig, direction: Direction) -> Ignores {
     let dirs = xdg_dirs().unwrap();
-    let mut ignored: HashSet<String> = HashSet::new();
     let home_dir = env::home_dir().unwrap();
+    let mut ignores = Ignores::new(settings.gitignore(), direction);
 
-    match dirs.find_config_file("ignore") {
-        Some(path) => read_ignore_file(home_dir.clone(), path, &mut ignored),
-        None => (),
-    };
+    // git's global excludes are the least specific of all
+    if settings.gitignore() {
+        ignores.add_global_file(git_root().as_path(), git_excludes_file().as_path());
+    }
 
-    let relative_pwd = relative_pwd().unwrap();
-    let mut search_path: Option<PathBuf> = None;
-    for component in relative_pwd.iter() {
-        let current_search = search_path.map_or(PathBuf::from(component), |s| {
-            s.join(component)
-        });
-        search_path = Some(current_search.clone());
-        let ignored_file: PathBuf = current_search.clone().join(".slink/ignore");
-        read_ignore_file(
-            home_dir.clone().join(current_search),
-            home_dir.join(ignored_file),
-            &mut ignored
-        );
+    if let Some(path) = dirs.find_config_file("ignore") {
+        ignores.add_global_file(home_dir.as_path(), path.as_path());
+    }
+    if direction == Direction::Down {
+        if let Some(path) = dirs.find_config_file("ignore-down") {
+            ignores.add_global_file(home_dir.as_path(), path.as_path());
+        }
     }
 
-    ignored
+    for dir in project_dirs() {
+        ignores.add_dir(dir.as_path());
+    }
+
+    ignores
 }
 
-fn read_ignore_file(root: PathBuf, path: PathBuf, ignored: &mut HashSet<String>) {
-    let mut contents = String::new();
-    match File::open(path.clone()) {
-        Err(_) => (),
-        Ok(mut file) => {
-            match file.read_to_string(&mut contents) {
-                Err(_) => (),
-                Ok(_) => {
-                    let lines = contents.split("\n");
-                    for line in lines {
-                        match parse_ignore(root.clone(), line) {
-                            Some(ignore) => { ignored.insert(ignore); },
-                            None => (),
-                        }
-                    }
-                }
+// The global excludes file git uses: core.excludesFile if it's set, or
+// git/ignore in the XDG config dir
+fn git_excludes_file() -> PathBuf {
+    let configured = process::output("git", |cmd| {
+        cmd.args(&["config", "--path", "--get", "core.excludesFile"]);
+    });
+
+    match configured {
+        Ok(ref path) if path.trim() != "" => PathBuf::from(path.trim()),
+        _ => {
+            let config_home = match env::var_os("XDG_CONFIG_HOME") {
+                Some(ref dir) if dir.len() > 0 => PathBuf::from(dir),
+                _ => env::home_dir().unwrap().join(".config"),
+            };
+            config_home.join("git/ignore")
+        },
+    }
+}
+
+// The root of the git repository containing the PWD, which anchored global
+// excludes are relative to. Falls back to the PWD outside a repository.
+fn git_root() -> PathBuf {
+    project_dirs().into_iter().rev()
+        .find(|dir| dir.join(".git").exists())
+        .unwrap_or_else(|| paths::pwd_or_panic())
+}
+
+/*
+ * Per-directory configuration, read from .slink/config files. Settings in
+ * files closer to the PWD override those further up the tree.
+ */
+#[derive(Deserialize, Debug, Default)]
+pub struct ProjectConfig {
+    // Name of the remote to use instead of the global default
+    #[serde(default)]
+    pub remote: Option<String>,
+
+    // Names of remotes to use for specific commands instead
+    #[serde(default)]
+    pub commands: CommandRemotes,
+
+    // What sync sends
+    #[serde(default)]
+    pub sync: SyncConfig,
+
+    // Ports go and run forward
+    #[serde(default)]
+    pub ports: PortsConfig,
+}
+
+/*
+ * Ports a project forwards whenever it's worked on, in the same forms slink
+ * forward takes them
+ */
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PortsConfig {
+    #[serde(default)]
+    forward: Option<Vec<PortSpec>>,
+    #[serde(default)]
+    reverse: Option<Vec<PortSpec>>,
+    #[serde(default)]
+    socks: Option<Vec<PortSpec>>,
+    // Also forward ports the remote starts listening on while go or run is
+    // running
+    #[serde(default)]
+    detect: Option<bool>,
+}
+
+// Bare ports are numbers in YAML, and anything with a colon is a string
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum PortSpec {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CommandRemotes {
+    #[serde(default)]
+    go: Option<String>,
+    #[serde(default)]
+    run: Option<String>,
+    #[serde(default)]
+    sync: Option<String>,
+    #[serde(default)]
+    upload: Option<String>,
+    #[serde(default)]
+    download: Option<String>,
+    #[serde(default)]
+    forward: Option<String>,
+}
+
+/*
+ * Settings for what gets synced, on top of .slink/ignore
+ */
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SyncConfig {
+    // Also leave out whatever git ignores
+    #[serde(default)]
+    gitignore: Option<bool>,
+    // Only send files git tracks
+    #[serde(default)]
+    git_tracked: Option<bool>,
+    // How many deletions sync --confirm allows before asking
+    #[serde(default)]
+    confirm_threshold: Option<usize>,
+    // How sync both resolves paths changed on both sides
+    #[serde(default)]
+    conflicts: Option<ConflictPolicy>,
+    // Move whatever a sync deletes or overwrites into a snapshot instead
+    #[serde(default)]
+    backup: Option<bool>,
+    // How many snapshots to keep
+    #[serde(default)]
+    keep_backups: Option<usize>,
+    // Patterns only ignored when syncing down, relative to the directory of
+    // the config. They apply below it like an ignore file, so aren't merged.
+    #[serde(default)]
+    ignore_down: Option<Vec<String>>,
+}
+
+/*
+ * How sync both resolves a path that changed on both sides since they were
+ * last in sync
+ */
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    LocalWins,
+    RemoteWins,
+    // Keep the local copy, and the remote one alongside it with a suffix
+    KeepBoth,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<ConflictPolicy, String> {
+        match policy {
+            "local-wins" => Ok(ConflictPolicy::LocalWins),
+            "remote-wins" => Ok(ConflictPolicy::RemoteWins),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            _ => Err(format!(
+                "unknown conflict policy {}; expected local-wins, remote-wins or keep-both",
+                policy
+            )),
+        }
+    }
+}
+
+impl ProjectConfig {
+    fn merge(&mut self, other: ProjectConfig) {
+        // Pinning a remote applies to every command in that tree, including
+        // ones overridden further up
+        if other.remote.is_some() {
+            self.remote = other.remote;
+            self.commands = CommandRemotes::default();
+        }
+        self.commands.merge(other.commands);
+        self.sync.merge(other.sync);
+        self.ports.merge(other.ports);
+    }
+}
+
+impl SyncConfig {
+    pub fn gitignore(&self) -> bool {
+        self.gitignore.unwrap_or(false)
+    }
+
+    pub fn git_tracked(&self) -> bool {
+        self.git_tracked.unwrap_or(false)
+    }
+
+    pub fn confirm_threshold(&self) -> usize {
+        self.confirm_threshold.unwrap_or(0)
+    }
+
+    pub fn conflicts(&self) -> Option<ConflictPolicy> {
+        self.conflicts
+    }
+
+    // Keeping no snapshots would prune the one a sync had just written, so
+    // that turns backups off
+    pub fn backup(&self) -> bool {
+        self.backup.unwrap_or(false) && self.keep_backups() > 0
+    }
+
+    pub fn keep_backups(&self) -> usize {
+        self.keep_backups.unwrap_or(10)
+    }
+
+    fn merge(&mut self, other: SyncConfig) {
+        if other.gitignore.is_some() { self.gitignore = other.gitignore; }
+        if other.git_tracked.is_some() { self.git_tracked = other.git_tracked; }
+        if other.confirm_threshold.is_some() { self.confirm_threshold = other.confirm_threshold; }
+        if other.conflicts.is_some() { self.conflicts = other.conflicts; }
+        if other.backup.is_some() { self.backup = other.backup; }
+        if other.keep_backups.is_some() { self.keep_backups = other.keep_backups; }
+    }
+}
+
+impl PortsConfig {
+    /*
+     * Every forward declared, validated the same way as on the command line
+     */
+    pub fn forwards(&self) -> SlinkResult<Vec<Forward>> {
+        let mut forwards = Vec::new();
+        try!(parse_specs(&self.forward, Forward::local, &mut forwards));
+        try!(parse_specs(&self.reverse, Forward::remote, &mut forwards));
+        try!(parse_specs(&self.socks, Forward::dynamic, &mut forwards));
+        Ok(forwards)
+    }
+
+    pub fn detect(&self) -> bool {
+        self.detect.unwrap_or(false)
+    }
+
+    fn merge(&mut self, other: PortsConfig) {
+        if other.forward.is_some() { self.forward = other.forward; }
+        if other.reverse.is_some() { self.reverse = other.reverse; }
+        if other.socks.is_some() { self.socks = other.socks; }
+        if other.detect.is_some() { self.detect = other.detect; }
+    }
+}
+
+fn parse_specs<F>(specs: &Option<Vec<PortSpec>>, parse: F, forwards: &mut Vec<Forward>)
+    -> SlinkResult<()>
+    where F: Fn(&str) -> Result<Forward, String>
+{
+    for spec in specs.iter().flat_map(|specs| specs.iter()) {
+        let spec = match *spec {
+            PortSpec::Number(port) => port.to_string(),
+            PortSpec::Text(ref spec) => spec.clone(),
+        };
+        forwards.push(try!(parse(spec.as_str()).map_err(Error::InvalidForward)));
+    }
+    Ok(())
+}
+
+impl CommandRemotes {
+    pub fn get(&self, command: CommandKind) -> Option<String> {
+        match command {
+            CommandKind::Go => self.go.clone(),
+            CommandKind::Run => self.run.clone(),
+            CommandKind::Sync => self.sync.clone(),
+            CommandKind::Upload => self.upload.clone(),
+            CommandKind::Download => self.download.clone(),
+            CommandKind::Forward => self.forward.clone(),
+        }
+    }
+
+    fn merge(&mut self, other: CommandRemotes) {
+        if other.go.is_some() { self.go = other.go; }
+        if other.run.is_some() { self.run = other.run; }
+        if other.sync.is_some() { self.sync = other.sync; }
+        if other.upload.is_some() { self.upload = other.upload; }
+        if other.download.is_some() { self.download = other.download; }
+        if other.forward.is_some() { self.forward = other.forward; }
+    }
+}
+
+pub fn project_config() -> SlinkResult<ProjectConfig> {
+    let mut config = ProjectConfig::default();
+
+    for dir in project_dirs() {
+        if let Some(dir_config) = try!(read_project_config(&dir)) {
+            config.merge(dir_config);
+        }
+    }
+
+    Ok(config)
+}
+
+/*
+ * The .slink/config that pins the remote for the PWD, if the global default
+ * isn't used
+ */
+pub fn remote_config_file() -> SlinkResult<Option<PathBuf>> {
+    // The nearest file wins
+    for dir in project_dirs().into_iter().rev() {
+        if let Some(dir_config) = try!(read_project_config(&dir)) {
+            if dir_config.remote.is_some() {
+                return Ok(Some(dir.join(".slink/config")));
             }
         }
+    }
+
+    Ok(None)
+}
+
+fn read_project_config(dir: &PathBuf) -> SlinkResult<Option<ProjectConfig>> {
+    let path = dir.join(".slink/config");
+    let mut file = match File::open(path) {
+        Err(_) => return Ok(None),
+        Ok(file) => file,
     };
+
+    let mut contents = String::new();
+    try!(file.read_to_string(&mut contents).map_err(|e| {
+        Error::FailedConfigRead(e)
+    }));
+
+    if contents.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let dir_config = try!(serde_yaml::from_str(contents.as_str()).map_err(|e| {
+        Error::MalformedConfig(e)
+    }));
+    Ok(Some(dir_config))
 }
 
-fn parse_ignore(path: PathBuf, ignore: &str) -> Option<String> {
-    if ignore == "" {
-        return None;
+/*
+ * The patterns in the sync.ignore_down section of a directory's .slink/config.
+ * Like an ignore file, a config that can't be read has none.
+ */
+pub fn ignore_down_patterns(dir: &Path) -> Vec<String> {
+    match read_project_config(&dir.to_path_buf()) {
+        Ok(Some(dir_config)) => dir_config.sync.ignore_down.unwrap_or(Vec::new()),
+        _ => Vec::new(),
     }
-    if ignore.chars().peekable().peek() != Some(&'/') {
-        return Some(String::from(ignore));
+}
+
+// Every directory that may contain a .slink config directory, starting just
+// below $HOME and ending at the PWD
+fn project_dirs() -> Vec<PathBuf> {
+    let home_dir = env::home_dir().unwrap();
+    let relative_pwd = relative_pwd().unwrap();
+
+    let mut dirs = Vec::new();
+    let mut search_path = home_dir;
+    for component in relative_pwd.iter() {
+        search_path = search_path.join(component);
+        dirs.push(search_path.clone());
     }
-    let mut chars = ignore.chars();
-    chars.next();
-    return Some(path.join(chars.as_str()).to_str().unwrap().into());
+
+    dirs
 }
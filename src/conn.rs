@@ -1,141 +1,408 @@
-use std::process::Command;
+use std::process::{Child, Command, Stdio};
 use std::vec::Vec;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::borrow::Cow;
 use std::convert;
+use std::env;
+use std::thread;
+use std::time::Duration;
 use isatty;
+use shell_escape;
 use process;
 use errors::SlinkResult;
-use config::{get_host, xdg_dirs};
+use config::xdg_dirs;
+use remote::SshRemote;
+use forward::{self, Forward};
+use relay;
+use transport::{self, ControlMaster, Transport};
+
+const SOCKET_PREFIX: &'static str = "conn-";
+const SOCKET_SUFFIX: &'static str = ".sock";
+
+// How long the shared connection is held open after its last use, unless the
+// remote says otherwise
+const DEFAULT_CONTROL_PERSIST: &'static str = "10m";
+
+// How long slink status waits for a connection before calling the remote
+// unreachable
+const PROBE_TIMEOUT_SECS: u64 = 5;
+
+// How often to check whether ssh has set up forwards that need relaying
+const FORWARD_WAIT_MS: u64 = 100;
 
 /*
- * Run an ssh command, passing the command as an argument to a closure for extra
- * configuration before running it
+ * The SSH transport. Every ssh, scp and rsync connection to a remote is
+ * multiplexed over a single cached connection.
  */
-pub fn ssh_command<F>(ssh_closure: F) -> SlinkResult<()>
-    where  F: FnOnce(&mut Command) -> ()
-{
-    let host = try!(get_host());
-    ssh_command_with_host(host.as_str(), ssh_closure)
+pub struct Ssh {
+    name: String,
+    remote: SshRemote,
+    // The remote to jump through to reach this one, if any
+    via: Option<String>,
 }
 
-pub fn port_forward(ports: Vec<String>) -> SlinkResult<()> {
-    let host = try!(get_host());
-
-    // Check for low ports, since those are privileged
-    let mut has_low_port = false;
-    let mut command = "ssh";
-    let mut port_forwards: Vec<String> = Vec::new();
-    for port in ports {
-        if port.parse::<i32>().unwrap() < 1024 {
-            has_low_port = true;
-            command = "sudo";
+impl Ssh {
+    pub fn new(name: &str, remote: &SshRemote, via: Option<String>) -> Ssh {
+        Ssh {
+            name: name.to_string(),
+            remote: remote.clone(),
+            via: via,
         }
-        port_forwards.push("-L".to_string());
-        port_forwards.push(format!("{}:127.0.0.1:{}", port, port));
     }
 
-    try!(process::run(command, |cmd| {
-        // If there's a low port, the command was just sudo. Actually
-        // invoke ssh now.
-        if has_low_port {
-            cmd.arg("ssh");
+    pub fn ssh_opts(&self) -> Vec<String> {
+        let sock_path = socket_path(self.name.as_str());
+        let sock_str = sock_path.to_str().unwrap();
+
+        let mut vec = Vec::with_capacity(6);
+        // "auto" ControlMaster setting means create a new connection if none
+        // exists, and use the existing one if available
+        vec.push(String::from("-oControlMaster=auto"));
+        // Use the passed-in socket string for the controlmaster path
+        vec.push(format!("-oControlPath={}", sock_str));
+        // Hang onto the shared connection for a while after exit
+        vec.push(format!("-oControlPersist={}", self.persist()));
+
+        // Jump through the via remote, like ProxyJump does. ProxyJump itself
+        // would start a fresh ssh for the jump that ignores these options, and
+        // so couldn't share the via remote's ControlMaster; going through
+        // `slink proxy` uses the via remote's own transport, and works however
+        // deeply remotes are nested.
+        if let Some(ref via) = self.via {
+            let slink = env::current_exe().unwrap();
+            let slink_str = slink.to_str().unwrap();
+            vec.push(format!(
+                "-oProxyCommand={} proxy {} %h %p",
+                shell_escape::escape(Cow::Borrowed(slink_str)),
+                shell_escape::escape(Cow::Borrowed(via.as_str()))
+            ));
         }
 
-        // Insert the options
-        cmd.args(ssh_opts(host.as_str()));
+        // Finally, how to reach the remote itself
+        vec.extend(self.remote.ssh_args());
 
-        // Disable shell
-        cmd.arg("-N");
+        vec
+    }
+
+    fn persist(&self) -> String {
+        self.remote.persist.clone().unwrap_or(String::from(DEFAULT_CONTROL_PERSIST))
+    }
 
-        // Set up port forwards
-        cmd.args(&port_forwards);
+    // Ask the master listening on the socket, if any, whether it's running.
+    // This never connects.
+    fn master_running(&self) -> bool {
+        let result = process::output("ssh", |cmd| {
+            cmd.args(self.ssh_opts());
+            cmd.arg("-q");
+            cmd.arg("-O");
+            cmd.arg("check");
+            cmd.arg(self.remote.hostname.as_str());
+            cmd.stderr(Stdio::null());
+        });
+
+        result.is_ok()
+    }
 
-        // Using the remote host
-        cmd.arg(host);
-    }));
+    // Send a control command to the master, like ssh -O
+    fn control(&self, command: &str, forwards: &[Forward]) -> SlinkResult<()> {
+        try!(process::run("ssh", |cmd| {
+            cmd.args(self.ssh_opts());
+            cmd.arg("-q");
+            cmd.arg("-O");
+            cmd.arg(command);
+            for forward in forwards {
+                cmd.args(forward.ssh_args());
+            }
+            cmd.arg(self.remote.hostname.as_str());
+        }));
 
-    Ok(())
+        Ok(())
+    }
+
+    fn scp<F>(&self, closure: F) -> SlinkResult<()>
+        where  F: FnOnce(&mut Command) -> ()
+    {
+        try!(process::run("scp", |cmd| {
+            // Insert the options
+            cmd.args(self.ssh_opts());
+            // Allow further configuration via the passed-in closure
+            closure(cmd);
+        }));
+
+        Ok(())
+    }
 }
 
-pub fn scp_up(from: PathBuf, to: PathBuf) -> SlinkResult<()> {
-    let host = try!(get_host());
-    scp(host.as_str(), |cmd| {
-        cmd.arg(from.to_str().unwrap());
-        cmd.arg(format!("{}:{}", host, to.to_str().unwrap()));
-    })
+// Where the ControlMaster socket for a remote lives
+fn socket_path(name: &str) -> PathBuf {
+    let dirs = xdg_dirs().unwrap();
+    dirs.place_cache_file(format!("{}{}{}", SOCKET_PREFIX, name, SOCKET_SUFFIX))
+        .expect("Could not create persistent socket file")
 }
 
-pub fn scp_down(from: PathBuf, to: PathBuf) -> SlinkResult<()> {
-    let host = try!(get_host());
-    scp(host.as_str(), |cmd| {
-        cmd.arg(format!("{}:{}", host, from.to_str().unwrap()));
-        cmd.arg(to.to_str().unwrap());
-    })
+// Marks a connection that slink connect --hold is keeping open
+fn hold_path(socket: &Path) -> PathBuf {
+    socket.with_extension("hold")
 }
 
-pub fn ssh_opts(host: &str) -> Vec<String> {
+/*
+ * Every ControlMaster socket slink has created, for any remote
+ */
+pub fn control_sockets() -> Vec<PathBuf> {
     let dirs = xdg_dirs().unwrap();
-    let sock_filename = format!("conn-{}.sock", host);
-    let sock_path = dirs.place_cache_file(sock_filename)
-                        .expect("Could not create persistent socket file");
-
-    let sock_str = sock_path.to_str().unwrap();
-
-    let mut vec = Vec::with_capacity(6);
-    // "auto" ControlMaster setting means create a new connection if none
-    // exists, and use the existing one if available
-    vec.push(String::from("-oControlMaster=auto"));
-    // Use the passed-in socket string for the controlmaster path
-    vec.push(format!("-oControlPath={}", sock_str));
-    // Hang onto the shared connection for 10mins after exit
-    vec.push(String::from("-oControlPersist=10m"));
-
-    vec
+    dirs.list_cache_files("").into_iter().filter(|path| {
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.starts_with(SOCKET_PREFIX) && name.ends_with(SOCKET_SUFFIX),
+            None => false,
+        }
+    }).collect()
 }
 
-// Run an ssh command, given the actual host and the socket string
-fn ssh_command_with_host<F>(host: &str, ssh_closure: F) -> SlinkResult<()>
-    where  F: FnOnce(&mut Command) -> ()
-{
-    let proc_result = process::run("ssh", |cmd| {
-        // Insert the options
-        cmd.args(ssh_opts(host));
-
-        // Force PTY allocation for interactivity if stdout is a tty
-        if isatty::stdout_isatty() {
-            cmd.arg("-t");
+/*
+ * Ask the ControlMaster listening on a socket to exit, and clean up the socket
+ * if it was stale
+ */
+pub fn exit_master(socket: &Path) {
+    // The socket path is given explicitly, so the host is just a placeholder
+    let _ = process::output("ssh", |cmd| {
+        cmd.arg(format!("-oControlPath={}", socket.to_str().unwrap()));
+        cmd.arg("-q");
+        cmd.arg("-O");
+        cmd.arg("exit");
+        cmd.arg("slink");
+        cmd.stderr(Stdio::null());
+    });
+
+    if socket.exists() {
+        let _ = fs::remove_file(socket);
+    }
+    let _ = fs::remove_file(hold_path(socket));
+}
+
+impl Transport for Ssh {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn command(&self, command: &str, tty: bool) -> SlinkResult<()> {
+        let proc_result = process::run("ssh", |cmd| {
+            // Insert the options
+            cmd.args(self.ssh_opts());
+
+            // Force PTY allocation for interactivity if stdout is a tty
+            if tty && isatty::stdout_isatty() {
+                cmd.arg("-t");
+            }
+
+            // Run in quiet mode
+            cmd.arg("-q");
+
+            // And finally, SSH to the given host and run the command
+            cmd.arg(self.remote.hostname.as_str());
+            cmd.arg(command);
+        });
+
+        match proc_result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(convert::From::from(e)),
         }
+    }
 
-        // Run in quiet mode
-        cmd.arg("-q");
+    fn output(&self, command: &str) -> SlinkResult<String> {
+        let output = try!(process::output("ssh", |cmd| {
+            cmd.args(self.ssh_opts());
+            cmd.arg("-q");
+            cmd.arg(self.remote.hostname.as_str());
+            cmd.arg(command);
+        }));
 
-        // And finally, SSH to the given host
-        cmd.arg(host);
-        // Allow further configuration via the passed-in closure
-        ssh_closure(cmd);
-    });
+        Ok(output)
+    }
 
-    match proc_result {
-        Ok(_) => Ok(()),
+    fn probe(&self, command: &str) -> SlinkResult<String> {
+        let output = try!(process::output("ssh", |cmd| {
+            cmd.args(self.ssh_opts());
+            cmd.arg(format!("-oConnectTimeout={}", PROBE_TIMEOUT_SECS));
+            cmd.arg("-q");
+            cmd.arg(self.remote.hostname.as_str());
+            cmd.arg(command);
+        }));
 
-        // 130 is 128+2, aka SIGINT. This appears to be generated sometimes when
-        // you log out of the remote connection -- not sure why? But doesn't
-        // appear to be a fatal error.
-        Err(process::Error::NonZeroExit(_, 130)) => Ok(()),
+        Ok(output)
+    }
 
-        Err(e) => Err(convert::From::from(e)),
+    fn upload(&self, from: &Path, to: &Path) -> SlinkResult<()> {
+        self.scp(|cmd| {
+            cmd.arg(from.to_str().unwrap());
+            cmd.arg(format!("{}:{}", self.remote.hostname, to.to_str().unwrap()));
+        })
+    }
+
+    fn download(&self, from: &Path, to: &Path) -> SlinkResult<()> {
+        self.scp(|cmd| {
+            cmd.arg(format!("{}:{}", self.remote.hostname, from.to_str().unwrap()));
+            cmd.arg(to.to_str().unwrap());
+        })
     }
-}
 
-fn scp<F>(host: &str, closure: F) -> SlinkResult<()>
-    where  F: FnOnce(&mut Command) -> ()
-{
-    try!(process::run("scp", |cmd| {
-        // Insert the options
-        cmd.args(ssh_opts(host));
-        // Allow further configuration via the passed-in closure
-        closure(cmd);
-    }));
-
-    Ok(())
+    fn rsync_shell(&self) -> String {
+        // rsync splits its remote shell on spaces, but respects quotes
+        let opts: Vec<String> = self.ssh_opts().iter().map(|opt| {
+            shell_escape::escape(Cow::Borrowed(opt.as_str())).into_owned()
+        }).collect();
+        format!("ssh {}", opts.join(" "))
+    }
+
+    fn rsync_host(&self) -> String {
+        self.remote.hostname.clone()
+    }
+
+    /*
+     * The remote side just waits for its stdin to close, so it also goes away
+     * if slink exits without cleaning up.
+     */
+    fn keep_alive(&self) -> SlinkResult<Option<Child>> {
+        let child = try!(process::spawn("ssh", |cmd| {
+            cmd.args(self.ssh_opts());
+            cmd.arg("-q");
+            cmd.arg(self.remote.hostname.as_str());
+            cmd.arg("cat > /dev/null");
+            cmd.stdin(Stdio::piped());
+        }));
+
+        Ok(Some(child))
+    }
+
+    fn control_master(&self) -> ControlMaster {
+        let hold = hold_path(socket_path(self.name.as_str()).as_path());
+
+        if !self.master_running() {
+            // The master went away without being disconnected
+            let _ = fs::remove_file(hold);
+            ControlMaster::Stopped
+        } else if hold.exists() {
+            ControlMaster::Held
+        } else {
+            ControlMaster::Running(self.persist())
+        }
+    }
+
+    /*
+     * Holding the connection open runs an ssh in the background that does
+     * nothing, so the master always has a client and never times out.
+     */
+    fn connect(&self, hold: bool) -> SlinkResult<()> {
+        // Holding again would start another background ssh
+        if let ControlMaster::Held = self.control_master() {
+            return Ok(());
+        }
+
+        let hold_file = hold_path(socket_path(self.name.as_str()).as_path());
+        if !self.master_running() {
+            let _ = fs::remove_file(&hold_file);
+        }
+
+        if hold {
+            try!(process::run("ssh", |cmd| {
+                cmd.args(self.ssh_opts());
+                cmd.arg("-q");
+                // Go into the background once connected, without running a
+                // command
+                cmd.arg("-f");
+                cmd.arg("-N");
+                cmd.arg(self.remote.hostname.as_str());
+                // The background ssh shouldn't keep slink's stdout open
+                cmd.stdout(Stdio::null());
+            }));
+
+            try!(File::create(&hold_file).map_err(transport::Error::FailedHold));
+        } else {
+            try!(self.output("true"));
+        }
+
+        Ok(())
+    }
+
+    fn disconnect(&self) -> SlinkResult<()> {
+        exit_master(socket_path(self.name.as_str()).as_path());
+        Ok(())
+    }
+
+    fn add_forwards(&self, forwards: &[Forward]) -> SlinkResult<()> {
+        self.control("forward", forwards)
+    }
+
+    fn cancel_forwards(&self, forwards: &[Forward]) -> SlinkResult<()> {
+        self.control("cancel", forwards)
+    }
+
+    /*
+     * Forward stdio over the shared connection, rather than relying on nc
+     */
+    fn proxy(&self, host: &str, port: u16) -> SlinkResult<()> {
+        try!(process::run("ssh", |cmd| {
+            cmd.args(self.ssh_opts());
+            cmd.arg("-q");
+            cmd.arg("-W");
+            cmd.arg(format!("{}:{}", host, port));
+            cmd.arg(self.remote.hostname.as_str());
+        }));
+
+        Ok(())
+    }
+
+    /*
+     * Privileged ports are forwarded from unprivileged ones, and a relay run
+     * with sudo listens on the ports themselves. ssh runs as the user, so it
+     * has their keys, config and agent, and shares their connection.
+     */
+    fn port_forward(&self, forwards: &[Forward]) -> SlinkResult<()> {
+        let (forwards, relays) = relay::split(forwards);
+        let mut port_forwards: Vec<String> = Vec::new();
+        for forward in forwards.iter() {
+            port_forwards.extend(forward.ssh_args());
+        }
+
+        let mut child = try!(process::spawn("ssh", |cmd| {
+            // Insert the options
+            cmd.args(self.ssh_opts());
+
+            // Disable shell
+            cmd.arg("-N");
+
+            // Fail rather than carrying on without a forward, e.g. if its
+            // port is taken
+            cmd.arg("-oExitOnForwardFailure=yes");
+
+            // Set up port forwards
+            cmd.args(&port_forwards);
+
+            // Using the remote host
+            cmd.arg(self.remote.hostname.as_str());
+        }));
+
+        if relays.is_empty() {
+            try!(process::wait("ssh", child));
+            return Ok(());
+        }
+
+        // Wait for the forwards to come up before asking for sudo, since ssh
+        // may still be asking for a password of its own
+        while !relays.iter().all(|relay| forward::port_in_use(relay.to)) {
+            if let Ok(Some(_)) = child.try_wait() {
+                try!(process::wait("ssh", child));
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(FORWARD_WAIT_MS));
+        }
+
+        // The relay exits when ssh does, or ssh is stopped if the relay fails
+        let relayed = relay::start(&relays, false);
+        let _ = child.kill();
+        let exited = process::wait("ssh", child);
+        try!(relayed);
+        try!(exited);
+        Ok(())
+    }
 }
@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use config::CommandKind;
+use errors::{self, SlinkResult};
+use forward::{self, Forward, DEFAULT_HOST};
+use transport::{self, ControlMaster, Transport};
+
+// How often the remote is checked for new listening ports
+const POLL_SECS: u64 = 2;
+
+// Every TCP socket on the remote, as the kernel lists them. Remotes without
+// /proc print nothing rather than failing.
+const SOCKETS_COMMAND: &'static str = "cat /proc/net/tcp /proc/net/tcp6 2>/dev/null; true";
+
+// The state /proc/net/tcp gives listening sockets
+const LISTEN: &'static str = "0A";
+
+/*
+ * Forwards the ports processes on the remote start listening on, over the
+ * shared connection, for as long as they keep listening
+ */
+pub struct Detector {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Detector {
+    /*
+     * Stop watching the remote, and cancel the forwards that were added
+     */
+    pub fn stop(self) {
+        // Hanging up wakes the thread
+        drop(self.stop);
+        let _ = self.thread.join();
+    }
+}
+
+/*
+ * Start watching the remote a command uses for new listening ports. Ports
+ * that are already being listened on are left alone, as are any that known
+ * forwards or detached forwards already cover. Returns None, after a warning,
+ * if the remote's ports can't be watched.
+ */
+pub fn start(transport: &dyn Transport, command: CommandKind, known: &[Forward])
+    -> SlinkResult<Option<Detector>>
+{
+    // Forwards are added to the shared connection, so there has to be one
+    if let ControlMaster::Unused = transport.control_master() {
+        eprintln!(
+            "Warning: can't detect ports on {}, since it doesn't share a connection",
+            transport.name()
+        );
+        return Ok(None);
+    }
+
+    let sockets = try!(transport.output(SOCKETS_COMMAND));
+    if !sockets.contains("local_address") {
+        eprintln!(
+            "Warning: can't detect ports on {}, since it has no /proc/net/tcp",
+            transport.name()
+        );
+        return Ok(None);
+    }
+
+    let mut covered = BTreeSet::new();
+    for forward in known {
+        add_covered(forward, &mut covered);
+    }
+    for detached in try!(forward::list()) {
+        if detached.remote == transport.name() {
+            add_covered(&detached.forward, &mut covered);
+        }
+    }
+
+    let mut watch = Watch {
+        covered: covered,
+        existing: listeners(&sockets).keys().cloned().collect(),
+        skipped: BTreeSet::new(),
+        forwarded: BTreeMap::new(),
+    };
+
+    // Transports can't be shared between threads, so the thread builds its
+    // own for the same remote
+    let (stop, stopped) = channel();
+    let thread = thread::spawn(move || {
+        if let Ok(transport) = transport::for_command(command) {
+            watch.run(&*transport, stopped);
+        }
+    });
+
+    Ok(Some(Detector { stop: stop, thread: thread }))
+}
+
+// The remote ports a forward already reaches, or that the remote is
+// listening on because of it
+fn add_covered(forward: &Forward, covered: &mut BTreeSet<u16>) {
+    match *forward {
+        Forward::Local { host_port, .. } => { covered.insert(host_port); },
+        Forward::Remote { port, .. } => { covered.insert(port); },
+        Forward::Dynamic { .. } => {},
+    }
+}
+
+struct Watch {
+    // Ports other forwards take care of
+    covered: BTreeSet<u16>,
+    // Ports that were listened on before watching started, until they stop
+    existing: BTreeSet<u16>,
+    // Ports that couldn't be forwarded, until they stop being listened on
+    skipped: BTreeSet<u16>,
+    forwarded: BTreeMap<u16, Forward>,
+}
+
+impl Watch {
+    fn run(&mut self, transport: &dyn Transport, stopped: Receiver<()>) {
+        let interval = Duration::from_secs(POLL_SECS);
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            self.poll(transport);
+        }
+
+        for (_, forward) in self.forwarded.iter() {
+            if transport.cancel_forwards(&[forward.clone()]).is_ok() {
+                let _ = forward::forget(transport.name(), forward);
+            }
+        }
+    }
+
+    fn poll(&mut self, transport: &dyn Transport) {
+        // Checking mustn't open a connection of its own, say while the
+        // command's still asking for a password
+        match transport.control_master() {
+            ControlMaster::Running(_) | ControlMaster::Held => {},
+            ControlMaster::Stopped | ControlMaster::Unused => return,
+        }
+
+        let listening = match transport.output(SOCKETS_COMMAND) {
+            Ok(sockets) => listeners(&sockets),
+            Err(_) => return,
+        };
+
+        let closed: Vec<u16> = self.forwarded.keys()
+            .filter(|port| !listening.contains_key(port))
+            .cloned()
+            .collect();
+        for port in closed {
+            let forward = self.forwarded.remove(&port).unwrap();
+            if transport.cancel_forwards(&[forward.clone()]).is_ok() {
+                let _ = forward::forget(transport.name(), &forward);
+                notice(&format!("Stopped forwarding {}, which stopped listening", forward));
+            }
+        }
+        self.existing.retain(|port| listening.contains_key(port));
+        self.skipped.retain(|port| listening.contains_key(port));
+
+        for (port, host) in listening {
+            if self.covered.contains(&port) || self.existing.contains(&port) ||
+                self.skipped.contains(&port) || self.forwarded.contains_key(&port) {
+                continue;
+            }
+
+            let forward = Forward::Local { port: port, host: host, host_port: port };
+
+            // Binding the same port here would need root
+            if forward.privileged() {
+                self.skipped.insert(port);
+                continue;
+            }
+
+            if forward::local_port_taken(&forward) {
+                notice(&format!(
+                    "Not forwarding {}, since local port {} is already in use", forward, port
+                ));
+                self.skipped.insert(port);
+                continue;
+            }
+
+            match transport.add_forwards(&[forward.clone()]) {
+                Ok(_) => {
+                    // If slink's killed before it can cancel the forward,
+                    // it's left to slink forward stop or disconnect
+                    let _ = forward::remember(transport.name(), &forward);
+                    notice(&format!("Forwarding {}, which just started listening", forward));
+                    self.forwarded.insert(port, forward);
+                },
+                Err(e) => {
+                    notice(&format!(
+                        "Warning: couldn't forward {}: {}", forward, errors::message(e)
+                    ));
+                    self.skipped.insert(port);
+                },
+            }
+        }
+    }
+}
+
+// Notices interrupt whatever the command is printing, and the terminal may be
+// in raw mode for it, so lines have to return the cursor themselves
+fn notice(message: &str) {
+    eprint!("{}\r\n", message);
+}
+
+// The ports sockets are listening on, each with the host a forward should
+// reach it at. Lines that aren't listening sockets, like the headers, are
+// skipped.
+fn listeners(sockets: &str) -> BTreeMap<u16, String> {
+    let mut ports = BTreeMap::new();
+
+    // Lines look like
+    // "   0: 0100007F:0BB8 00000000:0000 0A 00000000:00000000 ..."
+    for line in sockets.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[3] != LISTEN {
+            continue;
+        }
+
+        let mut local = fields[1].split(':');
+        let (address, port) = match (local.next(), local.next()) {
+            (Some(address), Some(port)) => (address, port),
+            _ => continue,
+        };
+        let port = match u16::from_str_radix(port, 16) {
+            Ok(port) => port,
+            Err(_) => continue,
+        };
+        let host = match host(address) {
+            Some(host) => host,
+            None => continue,
+        };
+
+        // A port can be listened on over both IPv4 and IPv6; loopback reaches
+        // it either way, so wins
+        let entry = ports.entry(port).or_insert(host.clone());
+        if host == DEFAULT_HOST {
+            *entry = host;
+        }
+    }
+
+    ports
+}
+
+// The host to reach a socket listening on an address at. Sockets listening on
+// every address are reached over loopback. The kernel prints addresses a
+// 32-bit word at a time in its own byte order, which is assumed to be little
+// endian.
+fn host(address: &str) -> Option<String> {
+    if address.len() % 8 != 0 {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(16);
+    for i in 0..address.len() / 8 {
+        let word = match u32::from_str_radix(&address[i * 8..(i + 1) * 8], 16) {
+            Ok(word) => word,
+            Err(_) => return None,
+        };
+        bytes.push(word as u8);
+        bytes.push((word >> 8) as u8);
+        bytes.push((word >> 16) as u8);
+        bytes.push((word >> 24) as u8);
+    }
+
+    match bytes.len() {
+        4 => {
+            let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+            if ip.is_unspecified() || ip.is_loopback() {
+                Some(DEFAULT_HOST.to_string())
+            } else {
+                Some(ip.to_string())
+            }
+        },
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&bytes);
+            let ip = Ipv6Addr::from(octets);
+            if ip.is_unspecified() {
+                Some(DEFAULT_HOST.to_string())
+            } else {
+                Some(ip.to_string())
+            }
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use forward::DEFAULT_HOST;
+    use super::{host, listeners};
+
+    const TCP: &'static str = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0BB8 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 1 1
+   1: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 2 1
+   2: 0200A8C0:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 3 1
+   3: 0100007F:A3C2 0100007F:0BB8 01 00000000:00000000 00:00000000 00000000  1000        0 4 1
+";
+
+    const TCP6: &'static str = "\
+  sl  local_address                         remote_address                        st tx_queue rx_queue
+   0: 00000000000000000000000001000000:1F91 00000000000000000000000000000000:0000 0A 00000000:00000000
+   1: 00000000000000000000000000000000:0BB9 00000000000000000000000000000000:0000 0A 00000000:00000000
+   2: 000080FE000000000000000001000000:1388 00000000000000000000000000000000:0000 0A 00000000:00000000
+   3: 00000000000000000000000000000000:1F90 00000000000000000000000000000000:0000 0A 00000000:00000000
+";
+
+    #[test]
+    fn ipv4_listeners() {
+        let ports = listeners(TCP);
+        assert_eq!(ports.len(), 3);
+        assert_eq!(ports[&3000], DEFAULT_HOST);
+        assert_eq!(ports[&8080], DEFAULT_HOST);
+        assert_eq!(ports[&22], "192.168.0.2");
+        // Connected sockets aren't listening
+        assert!(!ports.contains_key(&41922));
+    }
+
+    #[test]
+    fn ipv6_listeners() {
+        let ports = listeners(TCP6);
+        assert_eq!(ports[&8081], "::1");
+        assert_eq!(ports[&3001], DEFAULT_HOST);
+        assert_eq!(ports[&5000], "fe80::1");
+    }
+
+    #[test]
+    fn loopback_wins_across_families() {
+        let sockets = format!("{}{}", TCP6.replace(":0BB9 ", ":0016 "), TCP);
+        let ports = listeners(&sockets);
+        assert_eq!(ports[&22], DEFAULT_HOST);
+        assert_eq!(ports[&8080], DEFAULT_HOST);
+    }
+
+    #[test]
+    fn hosts() {
+        assert_eq!(host("0100007F"), Some(String::from(DEFAULT_HOST)));
+        assert_eq!(host("00000000"), Some(String::from(DEFAULT_HOST)));
+        assert_eq!(host("0A01A8C0"), Some(String::from("192.168.1.10")));
+        assert_eq!(host("00000000000000000000000000000000"), Some(String::from(DEFAULT_HOST)));
+        assert_eq!(host("B80D0120000000000000000001000000"), Some(String::from("2001:db8::1")));
+        assert_eq!(host("0100007"), None);
+        assert_eq!(host("0100007G"), None);
+        assert_eq!(host(""), None);
+    }
+}
@@ -1,5 +1,14 @@
+use std::fmt::Display;
+use std::process::exit;
 use process;
 use config;
+use notify;
+use transport;
+use bisync;
+use backup;
+use forward;
+use output;
+use paths;
 
 pub type SlinkResult<T> = Result<T, SlinkError>;
 pub enum SlinkError {
@@ -9,6 +18,12 @@ pub enum SlinkError {
      */
     ProcessError(process::Error<'static>),
     ConfigError(config::Error),
+    WatchError(notify::Error),
+    TransportError(transport::Error),
+    SyncError(bisync::Error),
+    BackupError(backup::Error),
+    ForwardError(forward::Error),
+    PathError(paths::Error),
 }
 
 impl From<process::Error<'static>> for SlinkError {
@@ -23,56 +38,267 @@ impl From<config::Error>for SlinkError {
     }
 }
 
-pub fn log_error_and_exit(err: SlinkError) {
-    println!("Slink encountered a fatal error:");
+impl From<notify::Error> for SlinkError {
+    fn from(e: notify::Error) -> SlinkError {
+        SlinkError::WatchError(e)
+    }
+}
+
+impl From<transport::Error> for SlinkError {
+    fn from(e: transport::Error) -> SlinkError {
+        SlinkError::TransportError(e)
+    }
+}
+
+impl From<backup::Error> for SlinkError {
+    fn from(e: backup::Error) -> SlinkError {
+        SlinkError::BackupError(e)
+    }
+}
+
+impl From<forward::Error> for SlinkError {
+    fn from(e: forward::Error) -> SlinkError {
+        SlinkError::ForwardError(e)
+    }
+}
+
+impl From<paths::Error> for SlinkError {
+    fn from(e: paths::Error) -> SlinkError {
+        SlinkError::PathError(e)
+    }
+}
+
+impl From<bisync::Error> for SlinkError {
+    fn from(e: bisync::Error) -> SlinkError {
+        SlinkError::SyncError(e)
+    }
+}
+
+/*
+ * For commands that stand in for a remote command: if the remote command
+ * failed, exit with its exit code and leave explaining why to it. Anything else
+ * is slink's own error.
+ */
+pub fn pass_through_exit(err: SlinkError) -> ! {
+    match err {
+        SlinkError::TransportError(transport::Error::RemoteExit(code)) => exit(code),
+        e => log_error_and_exit(e),
+    }
+}
+
+pub fn log_error_and_exit(err: SlinkError) -> ! {
+    let fatal = describe(err);
+
+    if output::json() {
+        output::print_json(&JsonError { error: &fatal });
+    } else {
+        eprintln!("Slink encountered a fatal error:");
+        eprintln!("{}", fatal.message);
+        if let Some(ref detail) = fatal.detail {
+            eprintln!("{}", detail);
+        }
+    }
+
+    exit(fatal.code)
+}
+
+/*
+ * Describe an error that isn't fatal, for a warning
+ */
+pub fn message(err: SlinkError) -> String {
+    let fatal = describe(err);
+    match fatal.detail {
+        Some(detail) => format!("{} {}", fatal.message, detail),
+        None => fatal.message,
+    }
+}
+
+// What went wrong, for people and for scripts alike
+#[derive(Serialize)]
+struct Fatal {
+    // The SlinkError variant, and the variant of the error it wraps
+    kind: &'static str,
+    variant: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    code: i32,
+}
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+    error: &'a Fatal,
+}
+
+fn fatal(kind: &'static str, variant: &'static str, message: String, code: i32) -> Fatal {
+    Fatal {
+        kind: kind,
+        variant: variant,
+        message: message,
+        detail: None,
+        code: code,
+    }
+}
+
+fn fatal_with_detail<D: Display>(kind: &'static str, variant: &'static str, message: &str,
+                                 detail: D, code: i32) -> Fatal {
+    Fatal {
+        kind: kind,
+        variant: variant,
+        message: message.to_string(),
+        detail: Some(detail.to_string()),
+        code: code,
+    }
+}
 
-    // Return the exit code from match, which makes the type system enforce you
-    // don't forget to have an exit (rather than trying to remember to always
-    // call exit() in the match arms).
-    let exit = match err {
+fn describe(err: SlinkError) -> Fatal {
+    // Each arm returns the exit code along with the description, which makes
+    // the type system enforce you don't forget to give an error one.
+    match err {
         SlinkError::ProcessError(proc_err) => {
+            let kind = "ProcessError";
             match proc_err {
                 process::Error::FailedToLaunch(name) => {
-                    println!("Failed to launch {}", name);
-                    2
+                    fatal(kind, "FailedToLaunch", format!("Failed to launch {}", name), 2)
                 },
 
                 process::Error::FailedToWait(name) => {
-                    println!("Couldn't wait for {}", name);
-                    3
+                    fatal(kind, "FailedToWait", format!("Couldn't wait for {}", name), 3)
                 },
 
                 process::Error::NonZeroExit(name, code) => {
-                    println!("{} exited with code {}", name, code);
-                    4
+                    fatal(kind, "NonZeroExit", format!("{} exited with code {}", name, code), 4)
                 },
 
                 process::Error::KilledBySignal(name) => {
-                    println!("{} killed by signal", name);
-                    5
+                    fatal(kind, "KilledBySignal", format!("{} killed by signal", name), 5)
                 },
             }
         },
         SlinkError::ConfigError(e) => {
+            let kind = "ConfigError";
             match e {
                 config::Error::NoConfigFile => {
-                    println!("No config file found; run slink use <host> to set up");
-                    6
+                    fatal(kind, "NoConfigFile",
+                          String::from("No config file found; run slink use <host> to set up"), 6)
                 },
                 config::Error::FailedConfigWrite(e) => {
-                    println!("Failed to write config file:");
-                    println!("{}", e);
-                    7
+                    fatal_with_detail(kind, "FailedConfigWrite", "Failed to write config file:", e, 7)
                 },
                 config::Error::FailedConfigRead(e) => {
-                    println!("Failed to read config file:");
-                    println!("{}", e);
-                    8
+                    fatal_with_detail(kind, "FailedConfigRead", "Failed to read config file:", e, 8)
+                },
+                config::Error::MalformedConfig(e) => {
+                    fatal_with_detail(kind, "MalformedConfig", "Config file is malformed:", e, 9)
+                },
+                config::Error::NoSuchRemote(name) => {
+                    fatal(kind, "NoSuchRemote",
+                          format!("No remote named {}; see slink remote list", name), 10)
+                },
+                config::Error::RemoteExists(name) => {
+                    fatal(kind, "RemoteExists", format!("A remote named {} already exists", name), 11)
+                },
+                config::Error::RemoteInUse(name, dependents) => {
+                    fatal(kind, "RemoteInUse", format!(
+                        "Can't remove {}, since {} reached through it: {}",
+                        name,
+                        if dependents.len() == 1 { "another remote is" } else { "other remotes are" },
+                        dependents.join(", ")
+                    ), 29)
+                },
+                config::Error::InvalidForward(reason) => {
+                    fatal(kind, "InvalidForward", format!("In .slink/config ports, {}", reason), 21)
                 },
             }
         },
-    };
-
-    // TODO: panic for developers; exit nonzero for users
-    panic!("Someday will exit with code {}", exit)
+        SlinkError::WatchError(e) => {
+            fatal_with_detail("WatchError", "WatchError", "Failed to watch for changes:", e, 12)
+        },
+        SlinkError::TransportError(e) => {
+            let kind = "TransportError";
+            match e {
+                transport::Error::Unsupported(name, operation) => {
+                    fatal(kind, "Unsupported",
+                          format!("Remote {} doesn't support {}", name, operation), 13)
+                },
+                transport::Error::NoMatchingPod(selector) => {
+                    fatal(kind, "NoMatchingPod",
+                          format!("No running pods match selector {}", selector), 14)
+                },
+                transport::Error::ViaCycle(chain) => {
+                    fatal(kind, "ViaCycle", format!("Remote is reached via itself: {}", chain), 15)
+                },
+                transport::Error::FailedHold(e) => {
+                    fatal_with_detail(kind, "FailedHold", "Failed to hold the connection open:", e, 27)
+                },
+                transport::Error::RemoteExit(code) => {
+                    fatal(kind, "RemoteExit", format!("The remote command exited with code {}", code), code)
+                },
+            }
+        },
+        SlinkError::SyncError(e) => {
+            match e {
+                bisync::Error::Unresolved(count) => {
+                    fatal("SyncError", "Unresolved", format!(
+                        "{} conflicting path{} left alone; pass --conflicts to resolve",
+                        count,
+                        if count == 1 { " was" } else { "s were" }
+                    ), 16)
+                },
+                bisync::Error::Emptied(side, state) => {
+                    fatal("SyncError", "Emptied", format!(
+                        "Everything in the {} copy is gone since the last sync both, so nothing was \
+                         deleted; if that's intended, remove {} and sync again",
+                        side,
+                        state.display()
+                    ), 25)
+                },
+                bisync::Error::NoMd5 => {
+                    fatal("SyncError", "NoMd5", String::from(
+                        "The remote has neither md5sum nor md5, which sync both needs to compare \
+                         files changed on both sides"
+                    ), 28)
+                },
+            }
+        },
+        SlinkError::BackupError(e) => {
+            let kind = "BackupError";
+            match e {
+                backup::Error::NothingToUndo => {
+                    fatal(kind, "NothingToUndo", String::from(
+                        "No backed-up sync of this directory with this remote to undo"
+                    ), 17)
+                },
+                backup::Error::FailedTrashUpdate(e) => {
+                    fatal_with_detail(kind, "FailedTrashUpdate", "Failed to update sync backups:", e, 18)
+                },
+            }
+        },
+        SlinkError::ForwardError(e) => {
+            let kind = "ForwardError";
+            match e {
+                forward::Error::NotForwarded(port) => {
+                    fatal(kind, "NotForwarded",
+                          format!("Port {} isn't forwarded in the background", port), 20)
+                },
+                forward::Error::FailedRelay(e) => {
+                    fatal_with_detail(kind, "FailedRelay", "Failed to relay privileged ports:", e, 22)
+                },
+            }
+        },
+        SlinkError::PathError(e) => {
+            let kind = "PathError";
+            match e {
+                paths::Error::UserHome(path) => {
+                    fatal(kind, "UserHome", format!(
+                        "Can't look up another user's home in {}; use an absolute path", path
+                    ), 23)
+                },
+                paths::Error::NoRemoteHome => {
+                    fatal(kind, "NoRemoteHome",
+                          String::from("Couldn't find $HOME on the remote to expand ~"), 24)
+                },
+            }
+        },
+    }
 }
@@ -2,9 +2,11 @@ use std::path::PathBuf;
 use std::borrow::Cow;
 use isatty;
 use shell_escape;
+use output;
 
 pub fn shell_in(path: PathBuf) -> String {
-    command_in(path, "$SHELL --login")
+    // Containers don't always set $SHELL
+    command_in(path, "${SHELL:-/bin/sh} --login")
 }
 
 pub fn command_in(path_buf: PathBuf, command: &str) -> String {
@@ -19,8 +21,9 @@ pub fn command_in(path_buf: PathBuf, command: &str) -> String {
     format!(
         "test -d {} {} && cd {} ; exec {}",
         escaped,
-        // Log a UI message about the directory assuming stdout is a tty
-        if isatty::stdout_isatty() {
+        // Log a UI message about the directory assuming stdout is a tty, and
+        // isn't being read by a script
+        if isatty::stdout_isatty() && !output::json() {
             format!("&& echo {}", escaped_echo)
         } else {
             String::new()
@@ -29,3 +32,16 @@ pub fn command_in(path_buf: PathBuf, command: &str) -> String {
         command
     )
 }
+
+/*
+ * Build a shell command line out of a program and its arguments, escaping each
+ * of them
+ */
+pub fn shell_join(program: &str, args: &[String]) -> String {
+    let mut command = String::from(program);
+    for arg in args {
+        command.push(' ');
+        command.push_str(&shell_escape::escape(Cow::Borrowed(arg.as_str())));
+    }
+    command
+}
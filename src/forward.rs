@@ -0,0 +1,564 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use serde_yaml;
+use config;
+use errors::{self, SlinkResult};
+use relay;
+use remote::Remotes;
+use transport::{self, ControlMaster, Transport};
+
+const DETACHED_FILE: &'static str = "forwards.yml";
+
+// Where forwards go when no host is given: the loopback interface on whichever
+// side the connection comes out
+pub const DEFAULT_HOST: &'static str = "127.0.0.1";
+
+pub enum Error {
+    NotForwarded(u16),
+    // The relay for privileged ports couldn't listen on one, or give up root
+    FailedRelay(io::Error),
+}
+
+/*
+ * A port forward, as one of ssh's -L, -R or -D flags sets up
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Forward {
+    // A local port reaching a host and port as seen from the remote
+    Local { port: u16, host: String, host_port: u16 },
+    // A port on the remote reaching a host and port as seen from here
+    Remote { port: u16, host: String, host_port: u16 },
+    // A local SOCKS proxy, whose connections are made from the remote
+    Dynamic { port: u16 },
+}
+
+impl Forward {
+    /*
+     * Parse a local forward: "port", "local:remote" or "local:host:remote"
+     */
+    pub fn local(spec: &str) -> Result<Forward, String> {
+        let (port, host, host_port) = try!(parse_mapping(spec));
+        Ok(Forward::Local { port: port, host: host, host_port: host_port })
+    }
+
+    /*
+     * Parse a reverse forward: "port", "remote:local" or "remote:host:local",
+     * in the same order as ssh -R
+     */
+    pub fn remote(spec: &str) -> Result<Forward, String> {
+        let (port, host, host_port) = try!(parse_mapping(spec));
+        Ok(Forward::Remote { port: port, host: host, host_port: host_port })
+    }
+
+    /*
+     * Parse a SOCKS proxy's local port
+     */
+    pub fn dynamic(spec: &str) -> Result<Forward, String> {
+        let port = try!(parse_port(spec).map_err(|e| invalid(spec, e)));
+        Ok(Forward::Dynamic { port: port })
+    }
+
+    /*
+     * The ssh flag and argument that set up the forward
+     */
+    pub fn ssh_args(&self) -> Vec<String> {
+        match *self {
+            Forward::Local { port, ref host, host_port } => {
+                vec![String::from("-L"), format!("{}:{}:{}", port, bracket(host), host_port)]
+            },
+            Forward::Remote { port, ref host, host_port } => {
+                vec![String::from("-R"), format!("{}:{}:{}", port, bracket(host), host_port)]
+            },
+            Forward::Dynamic { port } => vec![String::from("-D"), port.to_string()],
+        }
+    }
+
+    /*
+     * The port the forward listens on, on whichever side that is
+     */
+    pub fn port(&self) -> u16 {
+        match *self {
+            Forward::Local { port, .. } |
+            Forward::Remote { port, .. } |
+            Forward::Dynamic { port } => port,
+        }
+    }
+
+    /*
+     * The same forward, listening on a different port
+     */
+    pub fn with_port(&self, port: u16) -> Forward {
+        match *self {
+            Forward::Local { ref host, host_port, .. } => {
+                Forward::Local { port: port, host: host.clone(), host_port: host_port }
+            },
+            Forward::Remote { ref host, host_port, .. } => {
+                Forward::Remote { port: port, host: host.clone(), host_port: host_port }
+            },
+            Forward::Dynamic { .. } => Forward::Dynamic { port: port },
+        }
+    }
+
+    /*
+     * Whether the forward listens on a local port only root can bind
+     */
+    pub fn privileged(&self) -> bool {
+        match *self {
+            Forward::Local { port, .. } | Forward::Dynamic { port } => port < 1024,
+            // The remote's the one listening
+            Forward::Remote { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Forward::Local { port, ref host, host_port } => {
+                write!(f, "{} to {}:{} on the remote", port, bracket(host), host_port)
+            },
+            Forward::Remote { port, ref host, host_port } => {
+                write!(f, "remote {} to {}:{} here", port, bracket(host), host_port)
+            },
+            Forward::Dynamic { port } => write!(f, "a SOCKS proxy on {}", port),
+        }
+    }
+}
+
+/*
+ * A forward added to a remote's shared connection, which lasts as long as the
+ * connection does
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Detached {
+    pub remote: String,
+    pub forward: Forward,
+    // For a privileged forward, the port the shared connection listens on
+    // instead, which a relay brings to the forward's own port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relayed_to: Option<u16>,
+}
+
+impl Detached {
+    // The forward as the shared connection has it
+    fn added(&self) -> Forward {
+        match self.relayed_to {
+            Some(port) => self.forward.with_port(port),
+            None => self.forward.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct DetachedState {
+    #[serde(default)]
+    forwards: Vec<Detached>,
+}
+
+/*
+ * Add forwards to the remote's shared connection, so they keep running in
+ * the background. Privileged ones are added on free ports instead, and
+ * relayed to their own with sudo.
+ */
+pub fn detach(transport: &dyn Transport, forwards: &[Forward]) -> SlinkResult<()> {
+    // Listening ports don't stop the shared connection timing out, so it's
+    // held open until disconnected
+    match transport.control_master() {
+        ControlMaster::Held => {},
+        _ => try!(transport.connect(true)),
+    }
+
+    let (added, relays) = relay::split(forwards);
+    try!(transport.add_forwards(&added));
+    if !relays.is_empty() {
+        // Nothing's remembered yet, so everything added has to go, or it
+        // would run unseen until the connection closes
+        if let Err(e) = relay::start(&relays, true) {
+            let _ = transport.cancel_forwards(&added);
+            return Err(e);
+        }
+    }
+
+    let mut state = try!(read_state());
+    // split keeps the forwards in order
+    for (forward, added) in forwards.iter().zip(added.iter()) {
+        let exists = state.forwards.iter().any(|detached| {
+            detached.remote == transport.name() && detached.forward == *forward
+        });
+        if !exists {
+            state.forwards.push(Detached {
+                remote: transport.name().to_string(),
+                forward: forward.clone(),
+                relayed_to: if forward.privileged() { Some(added.port()) } else { None },
+            });
+        }
+    }
+    write_state(&state)
+}
+
+/*
+ * Remember a forward added to a remote's shared connection some other way, so
+ * that it's listed and can be stopped like the detached ones
+ */
+pub fn remember(remote: &str, forward: &Forward) -> SlinkResult<()> {
+    let mut state = try!(read_state());
+    state.forwards.push(Detached {
+        remote: remote.to_string(),
+        forward: forward.clone(),
+        relayed_to: None,
+    });
+    write_state(&state)
+}
+
+/*
+ * Forget a remembered forward, once it's been cancelled
+ */
+pub fn forget(remote: &str, forward: &Forward) -> SlinkResult<()> {
+    let mut state = try!(read_state());
+    state.forwards.retain(|detached| detached.remote != remote || detached.forward != *forward);
+    write_state(&state)
+}
+
+/*
+ * Start whichever of a project's forwards aren't running yet. Problems with
+ * a forward are only warnings, so they don't get in the way of the command
+ * the forwards are for. They run in the background like detached ones, so
+ * the shared connection is held open for them, which is pointed out if this
+ * is what opened it.
+ */
+pub fn ensure(transport: &dyn Transport, forwards: &[Forward]) -> SlinkResult<()> {
+    let was_held = held(transport);
+    let running = try!(list());
+    let mut privileged = Vec::new();
+
+    for forward in forwards {
+        let detached = running.iter().any(|detached| {
+            detached.remote == transport.name() && detached.forward == *forward
+        });
+        if detached {
+            continue;
+        }
+
+        if local_port_taken(forward) {
+            eprintln!(
+                "Warning: not forwarding {}, since local port {} is already in use",
+                forward,
+                forward.port()
+            );
+            continue;
+        }
+
+        // Relayed together, so sudo's only run once
+        if forward.privileged() {
+            privileged.push(forward.clone());
+        } else if let Err(e) = detach(transport, &[forward.clone()]) {
+            eprintln!("Warning: couldn't forward {}: {}", forward, errors::message(e));
+        }
+    }
+
+    if !privileged.is_empty() {
+        if let Err(e) = detach(transport, &privileged) {
+            let descriptions: Vec<String> = privileged.iter().map(|f| f.to_string()).collect();
+            eprintln!(
+                "Warning: couldn't forward {}: {}",
+                descriptions.join(", "),
+                errors::message(e)
+            );
+        }
+    }
+
+    if !was_held && held(transport) {
+        eprintln!(
+            "Holding the connection to {} open to forward the project's ports; \
+             run slink disconnect to close it and stop them",
+            transport.name()
+        );
+    }
+
+    Ok(())
+}
+
+fn held(transport: &dyn Transport) -> bool {
+    match transport.control_master() {
+        ControlMaster::Held => true,
+        _ => false,
+    }
+}
+
+/*
+ * Whether something's already listening on the local port a forward needs.
+ * Only root can bind privileged ports, so there's no telling for those; they
+ * count as free, and whatever listens on them finds out.
+ */
+pub fn local_port_taken(forward: &Forward) -> bool {
+    match *forward {
+        Forward::Remote { .. } => false,
+        _ if forward.privileged() => false,
+        Forward::Local { port, .. } | Forward::Dynamic { port } => port_in_use(port),
+    }
+}
+
+/*
+ * Whether something's listening on a local port, found by trying to listen on
+ * it. Unlike connecting, this doesn't disturb whatever's there.
+ */
+pub fn port_in_use(port: u16) -> bool {
+    match TcpListener::bind((DEFAULT_HOST, port)) {
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => true,
+        _ => false,
+    }
+}
+
+/*
+ * The detached forwards that are still running. Forwards whose connection has
+ * closed since are forgotten.
+ */
+pub fn list() -> SlinkResult<Vec<Detached>> {
+    let mut state = try!(read_state());
+    let remotes = try!(Remotes::load());
+
+    let mut open = Vec::new();
+    let mut closed = Vec::new();
+    let count = state.forwards.len();
+    state.forwards.retain(|detached| {
+        if open.contains(&detached.remote) {
+            return true;
+        }
+        if closed.contains(&detached.remote) {
+            return false;
+        }
+
+        let running = match remotes.get(detached.remote.as_str()) {
+            Err(_) => false,
+            Ok(remote) => match transport::for_remote(&remotes, &remote) {
+                Err(_) => false,
+                Ok(transport) => match transport.control_master() {
+                    ControlMaster::Running(_) | ControlMaster::Held => true,
+                    ControlMaster::Stopped | ControlMaster::Unused => false,
+                },
+            },
+        };
+
+        if running {
+            open.push(detached.remote.clone());
+        } else {
+            closed.push(detached.remote.clone());
+        }
+        running
+    });
+
+    if state.forwards.len() != count {
+        try!(write_state(&state));
+    }
+    Ok(state.forwards)
+}
+
+/*
+ * Remove the detached forwards listening on a port, on any remote. Returns
+ * what was removed.
+ */
+pub fn stop(port: u16) -> SlinkResult<Vec<Detached>> {
+    let remotes = try!(Remotes::load());
+    let mut state = DetachedState { forwards: try!(list()) };
+
+    let (stopping, keeping): (Vec<Detached>, Vec<Detached>) = state.forwards.into_iter()
+        .partition(|detached| detached.forward.port() == port);
+    state.forwards = keeping;
+
+    if stopping.is_empty() {
+        return Err(Error::NotForwarded(port).into());
+    }
+
+    for detached in stopping.iter() {
+        let remote = try!(remotes.get(detached.remote.as_str()));
+        let transport = try!(transport::for_remote(&remotes, &remote));
+        // A relay stops once the port it relays to is no longer forwarded
+        try!(transport.cancel_forwards(&[detached.added()]));
+    }
+
+    try!(write_state(&state));
+    Ok(stopping)
+}
+
+fn state_file() -> PathBuf {
+    let dirs = config::xdg_dirs().unwrap();
+    dirs.place_cache_file(DETACHED_FILE).expect("Could not create forward state file")
+}
+
+fn read_state() -> SlinkResult<DetachedState> {
+    let file = match File::open(state_file()) {
+        Err(_) => return Ok(DetachedState::default()),
+        Ok(file) => file,
+    };
+
+    let state = try!(serde_yaml::from_reader(file).map_err(|e| {
+        config::Error::MalformedConfig(e)
+    }));
+    Ok(state)
+}
+
+fn write_state(state: &DetachedState) -> SlinkResult<()> {
+    let file = try!(File::create(state_file()).map_err(|e| {
+        config::Error::FailedConfigWrite(e)
+    }));
+
+    try!(serde_yaml::to_writer(file, state).map_err(|e| {
+        config::Error::MalformedConfig(e)
+    }));
+    Ok(())
+}
+
+// A port on one side, and the host and port it reaches on the other
+fn parse_mapping(spec: &str) -> Result<(u16, String, u16), String> {
+    let parts = try!(split(spec).map_err(|e| invalid(spec, e)));
+
+    let (port, host, host_port) = match parts.len() {
+        1 => (parts[0], DEFAULT_HOST, parts[0]),
+        2 => (parts[0], DEFAULT_HOST, parts[1]),
+        3 => (parts[0], parts[1], parts[2]),
+        _ => return Err(invalid(spec, String::from("expected at most port:host:port"))),
+    };
+
+    if host.is_empty() {
+        return Err(invalid(spec, String::from("the host is empty")));
+    }
+
+    let port = try!(parse_port(port).map_err(|e| invalid(spec, e)));
+    let host_port = try!(parse_port(host_port).map_err(|e| invalid(spec, e)));
+    Ok((port, host.to_string(), host_port))
+}
+
+// Split on colons, except inside the brackets around an IPv6 address
+fn split(spec: &str) -> Result<Vec<&str>, String> {
+    let mut parts = Vec::new();
+    let mut rest = spec;
+
+    loop {
+        if rest.starts_with('[') {
+            let end = match rest.find(']') {
+                Some(end) => end,
+                None => return Err(String::from("a [ isn't closed")),
+            };
+            parts.push(&rest[1..end]);
+            rest = &rest[end + 1..];
+
+            if rest.is_empty() {
+                break;
+            }
+            if !rest.starts_with(':') {
+                return Err(String::from("expected a : after ]"));
+            }
+            rest = &rest[1..];
+        } else {
+            match rest.find(':') {
+                Some(colon) => {
+                    parts.push(&rest[..colon]);
+                    rest = &rest[colon + 1..];
+                },
+                None => {
+                    parts.push(rest);
+                    break;
+                },
+            }
+        }
+    }
+
+    Ok(parts)
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!("{} isn't a port number from 1 to 65535", quote(port))),
+    }
+}
+
+fn invalid(spec: &str, reason: String) -> String {
+    format!("invalid forward {}: {}", quote(spec), reason)
+}
+
+fn quote(s: &str) -> String {
+    if s.is_empty() { String::from("\"\"") } else { s.to_string() }
+}
+
+// IPv6 addresses need brackets to be told apart from the ports around them
+fn bracket(host: &str) -> String {
+    if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Forward, DEFAULT_HOST};
+
+    fn local(port: u16, host: &str, host_port: u16) -> Forward {
+        Forward::Local { port: port, host: host.to_string(), host_port: host_port }
+    }
+
+    #[test]
+    fn same_port() {
+        assert_eq!(Forward::local("8080"), Ok(local(8080, DEFAULT_HOST, 8080)));
+    }
+
+    #[test]
+    fn different_ports() {
+        assert_eq!(Forward::local("8080:80"), Ok(local(8080, DEFAULT_HOST, 80)));
+    }
+
+    #[test]
+    fn host_and_ports() {
+        assert_eq!(Forward::local("8080:db:5432"), Ok(local(8080, "db", 5432)));
+        assert_eq!(
+            Forward::remote("9000:localhost:3000"),
+            Ok(Forward::Remote { port: 9000, host: String::from("localhost"), host_port: 3000 })
+        );
+    }
+
+    #[test]
+    fn bracketed_ipv6_hosts() {
+        let forward = Forward::local("8080:[::1]:80").unwrap();
+        assert_eq!(forward, local(8080, "::1", 80));
+        assert_eq!(forward.ssh_args(), vec!["-L", "8080:[::1]:80"]);
+
+        assert_eq!(Forward::local("8080:[fe80::1:2]:80"), Ok(local(8080, "fe80::1:2", 80)));
+        assert!(Forward::local("8080:[::1:80").is_err());
+        assert!(Forward::local("8080:[::1]80").is_err());
+    }
+
+    #[test]
+    fn empty_host() {
+        assert!(Forward::local("8080::80").is_err());
+        assert!(Forward::local("8080:[]:80").is_err());
+    }
+
+    #[test]
+    fn out_of_range_ports() {
+        assert!(Forward::local("0").is_err());
+        assert!(Forward::local("8080:0").is_err());
+        assert!(Forward::local("65536").is_err());
+        assert!(Forward::local("8080:db:70000").is_err());
+        assert!(Forward::dynamic("0").is_err());
+        assert!(Forward::local("").is_err());
+        assert!(Forward::local("http").is_err());
+        assert_eq!(Forward::local("65535"), Ok(local(65535, DEFAULT_HOST, 65535)));
+    }
+
+    #[test]
+    fn too_many_parts() {
+        assert!(Forward::local("1:db:2:3").is_err());
+        assert!(Forward::remote("1:2:3:4:5").is_err());
+    }
+
+    #[test]
+    fn socks_proxies() {
+        assert_eq!(Forward::dynamic("1080"), Ok(Forward::Dynamic { port: 1080 }));
+        assert!(Forward::dynamic("1080:80").is_err());
+    }
+}
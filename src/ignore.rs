@@ -0,0 +1,585 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use config;
+
+/*
+ * A set of gitignore-style patterns, each scoped to the directory of the
+ * ignore file it came from. Later patterns take precedence over earlier ones,
+ * so files should be added from the top of the tree down.
+ */
+pub struct Ignores {
+    patterns: Vec<Pattern>,
+    // Whether git's ignore files count too
+    gitignore: bool,
+    direction: Direction,
+}
+
+/*
+ * Which way files are being synced, since some patterns only apply one way
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/*
+ * A path in a tree being synced, relative to the root of the tree
+ */
+#[derive(Serialize, Debug, Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+struct Pattern {
+    // The directory the pattern is relative to
+    base: PathBuf,
+    // Patterns from the global ignore file match names anywhere
+    global: bool,
+    // The line the pattern came from, for debugging
+    source: String,
+    tokens: Vec<Token>,
+    negated: bool,
+    dir_only: bool,
+    // Patterns with a slash anywhere but the end match the path relative to
+    // the base; others match the name at any depth
+    anchored: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Char(char),
+    // ?
+    AnyChar,
+    // *, which doesn't cross directories
+    Star,
+    // A trailing /**, which matches everything inside
+    DoubleStar,
+    // **/, which matches zero or more directories
+    Dirs,
+    // [...], with the ranges it contains and whether it's negated
+    Class(Vec<(char, char)>, bool),
+}
+
+impl Ignores {
+    pub fn new(gitignore: bool, direction: Direction) -> Ignores {
+        Ignores { patterns: Vec::new(), gitignore: gitignore, direction: direction }
+    }
+
+    /*
+     * Add the patterns from every ignore file that lives in a directory.
+     * .slink/ignore comes after git's, so it can re-include things git
+     * ignores, and .slink/ignore-down and the sync.ignore_down section of
+     * .slink/config after that when syncing down.
+     */
+    pub fn add_dir(&mut self, dir: &Path) {
+        if self.gitignore {
+            self.add_file(dir, dir.join(".git/info/exclude").as_path());
+            self.add_file(dir, dir.join(".gitignore").as_path());
+        }
+        self.add_file(dir, dir.join(".slink/ignore").as_path());
+        if self.direction == Direction::Down {
+            self.add_file(dir, dir.join(".slink/ignore-down").as_path());
+            for line in config::ignore_down_patterns(dir) {
+                self.add_line(dir, line.as_str(), false);
+            }
+        }
+    }
+
+    /*
+     * Add the patterns from an ignore file, relative to base. Files that can't
+     * be read are skipped.
+     */
+    pub fn add_file(&mut self, base: &Path, path: &Path) {
+        self.read_file(base, path, false);
+    }
+
+    /*
+     * Like add_file, but unanchored patterns in the file match everywhere, not
+     * just under base.
+     */
+    pub fn add_global_file(&mut self, base: &Path, path: &Path) {
+        self.read_file(base, path, true);
+    }
+
+    fn read_file(&mut self, base: &Path, path: &Path, global: bool) {
+        let mut contents = String::new();
+        match File::open(path) {
+            Err(_) => return,
+            Ok(mut file) => {
+                if file.read_to_string(&mut contents).is_err() {
+                    return;
+                }
+            },
+        };
+
+        for line in contents.lines() {
+            self.add_line(base, line, global);
+        }
+    }
+
+    fn add_line(&mut self, base: &Path, line: &str, global: bool) {
+        if let Some(pattern) = Pattern::parse(base, line, global) {
+            self.patterns.push(pattern);
+        }
+    }
+
+    /*
+     * Whether the patterns ignore an absolute path, without considering
+     * whether any of its parents are ignored.
+     */
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for pattern in self.patterns.iter() {
+            if pattern.matches(path, is_dir) {
+                ignored = !pattern.negated;
+            }
+        }
+        ignored
+    }
+
+    /*
+     * Whether a path relative to root is ignored, either itself or because one
+     * of its parent directories is.
+     */
+    pub fn is_ignored(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let mut current = root.to_path_buf();
+        let mut components = path.components().peekable();
+
+        while let Some(component) = components.next() {
+            current.push(component.as_os_str());
+            let current_is_dir = components.peek().is_some() || is_dir;
+            if self.matches(current.as_path(), current_is_dir) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /*
+     * Walk the local tree under root, picking up any ignore files inside it as
+     * they're found. Returns the topmost ignored paths; nothing inside an
+     * ignored directory is visited.
+     */
+    pub fn walk(&mut self, root: &Path) -> Vec<Entry> {
+        let mut ignored = Vec::new();
+        self.walk_dir(root, PathBuf::new(), &mut ignored, &mut Vec::new());
+        ignored
+    }
+
+    /*
+     * Like walk, but returns every file that isn't ignored instead
+     */
+    pub fn files(&mut self, root: &Path) -> Vec<Entry> {
+        let mut files = Vec::new();
+        self.walk_dir(root, PathBuf::new(), &mut Vec::new(), &mut files);
+        files
+    }
+
+    fn walk_dir(&mut self, root: &Path, rel_dir: PathBuf, ignored: &mut Vec<Entry>, files: &mut Vec<Entry>) {
+        let dir = root.join(&rel_dir);
+
+        // The root's own ignore files were read along with its parents'
+        if rel_dir.as_os_str().len() > 0 {
+            self.add_dir(dir.as_path());
+        }
+
+        let mut children: Vec<(PathBuf, bool)> = match fs::read_dir(&dir) {
+            Err(_) => return,
+            Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| {
+                // Don't follow symlinks; rsync copies them as links
+                let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                (rel_dir.join(entry.file_name()), is_dir)
+            }).collect(),
+        };
+        children.sort();
+
+        for (path, is_dir) in children {
+            if self.matches(root.join(&path).as_path(), is_dir) {
+                ignored.push(Entry { path: path, is_dir: is_dir });
+            } else if is_dir {
+                self.walk_dir(root, path, ignored, files);
+            } else {
+                files.push(Entry { path: path, is_dir: false });
+            }
+        }
+    }
+
+    /*
+     * Like walk, but for a listing of a tree that isn't local, e.g. the
+     * remote's copy of root. The listing must have parents before children.
+     */
+    pub fn filter(&self, root: &Path, entries: &[Entry]) -> Vec<Entry> {
+        let mut ignored: Vec<Entry> = Vec::new();
+
+        for entry in entries.iter() {
+            let inside_ignored = ignored.iter().any(|dir| {
+                dir.is_dir && entry.path.starts_with(&dir.path)
+            });
+            if inside_ignored {
+                continue;
+            }
+
+            if self.matches(root.join(&entry.path).as_path(), entry.is_dir) {
+                ignored.push(entry.clone());
+            }
+        }
+
+        ignored
+    }
+
+    /*
+     * Describe each pattern and where it came from
+     */
+    pub fn describe(&self) -> Vec<String> {
+        self.patterns.iter().map(|pattern| {
+            format!("{}: {}", pattern.base.display(), pattern.source)
+        }).collect()
+    }
+}
+
+impl Pattern {
+    fn parse(base: &Path, line: &str, global: bool) -> Option<Pattern> {
+        // Trailing spaces are ignored unless escaped
+        let mut line = line.trim_end_matches('\r').to_string();
+        while line.ends_with(' ') && !line.ends_with("\\ ") {
+            line.pop();
+        }
+
+        if line == "" || line.starts_with('#') {
+            return None;
+        }
+
+        let source = line.clone();
+        let mut pattern = line.as_str();
+
+        let negated = pattern.starts_with('!');
+        if negated {
+            pattern = &pattern[1..];
+        }
+
+        // \# and \! escape patterns that would otherwise be comments or
+        // negations
+        if pattern.starts_with("\\#") || pattern.starts_with("\\!") {
+            pattern = &pattern[1..];
+        }
+
+        let dir_only = pattern.ends_with('/');
+        if dir_only {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+
+        let anchored = pattern.contains('/');
+        if pattern.starts_with('/') {
+            pattern = &pattern[1..];
+        }
+
+        if pattern == "" {
+            return None;
+        }
+
+        Some(Pattern {
+            base: base.to_path_buf(),
+            global: global,
+            source: source,
+            tokens: tokenize(pattern),
+            negated: negated,
+            dir_only: dir_only,
+            anchored: anchored,
+        })
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let text: Vec<char> = if self.anchored {
+            match path.strip_prefix(&self.base) {
+                Ok(rel_path) => rel_path.to_string_lossy().chars().collect(),
+                Err(_) => return false,
+            }
+        } else {
+            if !self.global && !path.starts_with(&self.base) {
+                return false;
+            }
+            match path.file_name() {
+                Some(name) => name.to_string_lossy().chars().collect(),
+                None => return false,
+            }
+        };
+
+        glob_match(&self.tokens, &text)
+    }
+}
+
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(Token::Char(chars[i + 1]));
+                i += 2;
+            },
+
+            '?' => {
+                tokens.push(Token::AnyChar);
+                i += 1;
+            },
+
+            '*' => {
+                let start = i;
+                while i < chars.len() && chars[i] == '*' {
+                    i += 1;
+                }
+
+                // Two or more stars only mean something special as a whole
+                // path component; otherwise they're the same as one
+                let whole_component = i - start >= 2 &&
+                    (start == 0 || chars[start - 1] == '/') &&
+                    (i == chars.len() || chars[i] == '/');
+
+                if !whole_component {
+                    tokens.push(Token::Star);
+                } else if i == chars.len() {
+                    tokens.push(Token::DoubleStar);
+                } else {
+                    tokens.push(Token::Dirs);
+                    // The slash is part of the token
+                    i += 1;
+                }
+            },
+
+            '[' => {
+                match parse_class(&chars[i + 1..]) {
+                    Some((token, len)) => {
+                        tokens.push(token);
+                        i += len + 1;
+                    },
+                    // An unterminated class is just a bracket
+                    None => {
+                        tokens.push(Token::Char('['));
+                        i += 1;
+                    },
+                }
+            },
+
+            c => {
+                tokens.push(Token::Char(c));
+                i += 1;
+            },
+        }
+    }
+
+    tokens
+}
+
+// Parse the inside of a [...] class, returning the token and how many chars it
+// used, including the closing bracket
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = i < chars.len() && (chars[i] == '!' || chars[i] == '^');
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = Vec::new();
+    let mut first = true;
+    while i < chars.len() {
+        // A ] right at the start is literal
+        if chars[i] == ']' && !first {
+            return Some((Token::Class(ranges, negated), i + 1));
+        }
+        first = false;
+
+        let mut start = chars[i];
+        if start == '\\' && i + 1 < chars.len() {
+            i += 1;
+            start = chars[i];
+        }
+
+        if i + 2 < chars.len() && chars[i + 1] == '-' && chars[i + 2] != ']' {
+            ranges.push((start, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((start, start));
+            i += 1;
+        }
+    }
+
+    None
+}
+
+fn glob_match(tokens: &[Token], text: &[char]) -> bool {
+    if tokens.is_empty() {
+        return text.is_empty();
+    }
+
+    match tokens[0] {
+        Token::Char(c) => {
+            !text.is_empty() && text[0] == c && glob_match(&tokens[1..], &text[1..])
+        },
+
+        Token::AnyChar => {
+            !text.is_empty() && text[0] != '/' && glob_match(&tokens[1..], &text[1..])
+        },
+
+        Token::Class(ref ranges, negated) => {
+            if text.is_empty() || text[0] == '/' {
+                return false;
+            }
+            let in_class = ranges.iter().any(|&(start, end)| {
+                start <= text[0] && text[0] <= end
+            });
+            in_class != negated && glob_match(&tokens[1..], &text[1..])
+        },
+
+        Token::Star => {
+            // Try every split that doesn't cross a directory
+            let mut i = 0;
+            loop {
+                if glob_match(&tokens[1..], &text[i..]) {
+                    return true;
+                }
+                if i == text.len() || text[i] == '/' {
+                    return false;
+                }
+                i += 1;
+            }
+        },
+
+        Token::DoubleStar => !text.is_empty(),
+
+        Token::Dirs => {
+            // Zero directories, or skip ahead to after each following slash
+            if glob_match(&tokens[1..], text) {
+                return true;
+            }
+            (0..text.len()).any(|i| {
+                text[i] == '/' && glob_match(&tokens[1..], &text[i + 1..])
+            })
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::{Direction, Ignores, Pattern};
+
+    // Ignores with the given lines, as if read from an ignore file in /root
+    fn ignores(lines: &[&str]) -> Ignores {
+        let mut ignores = Ignores::new(false, Direction::Up);
+        for line in lines {
+            if let Some(pattern) = Pattern::parse(Path::new("/root"), line, false) {
+                ignores.patterns.push(pattern);
+            }
+        }
+        ignores
+    }
+
+    fn ignored(ignores: &Ignores, path: &str, is_dir: bool) -> bool {
+        ignores.is_ignored(Path::new("/root"), Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let ignores = ignores(&["# a.log", "", "   ", "\\#b"]);
+        assert_eq!(ignores.patterns.len(), 1);
+        assert!(!ignored(&ignores, "# a.log", false));
+        assert!(ignored(&ignores, "#b", false));
+    }
+
+    #[test]
+    fn negation() {
+        let ignores = ignores(&["*.log", "!keep.log"]);
+        assert!(ignored(&ignores, "debug.log", false));
+        assert!(!ignored(&ignores, "keep.log", false));
+        assert!(!ignored(&ignores, "sub/keep.log", false));
+    }
+
+    #[test]
+    fn later_patterns_win() {
+        let ignores = ignores(&["!keep.log", "*.log"]);
+        assert!(ignored(&ignores, "keep.log", false));
+    }
+
+    #[test]
+    fn double_star() {
+        let ignores = ignores(&["**/build", "docs/**", "a/**/z"]);
+        assert!(ignored(&ignores, "build", true));
+        assert!(ignored(&ignores, "x/y/build", true));
+        assert!(ignored(&ignores, "docs/index.md", false));
+        assert!(!ignored(&ignores, "docs", true));
+        assert!(ignored(&ignores, "a/z", false));
+        assert!(ignored(&ignores, "a/b/c/z", false));
+        assert!(!ignored(&ignores, "b/z", false));
+    }
+
+    #[test]
+    fn dir_only() {
+        let ignores = ignores(&["target/"]);
+        assert!(ignored(&ignores, "target", true));
+        assert!(ignored(&ignores, "sub/target", true));
+        assert!(!ignored(&ignores, "target", false));
+    }
+
+    #[test]
+    fn anchoring() {
+        let ignores = ignores(&["/top", "a/b", "name"]);
+        assert!(ignored(&ignores, "top", false));
+        assert!(!ignored(&ignores, "sub/top", false));
+        assert!(ignored(&ignores, "a/b", false));
+        assert!(!ignored(&ignores, "x/a/b", false));
+        assert!(ignored(&ignores, "name", false));
+        assert!(ignored(&ignores, "x/y/name", false));
+    }
+
+    #[test]
+    fn wildcards() {
+        let names = ignores(&["*.o", "file?.txt"]);
+        assert!(ignored(&names, "main.o", false));
+        assert!(!ignored(&names, "main.obj", false));
+        assert!(ignored(&names, "file1.txt", false));
+        assert!(!ignored(&names, "file10.txt", false));
+
+        // * doesn't cross directories in anchored patterns
+        let paths = ignores(&["src/*.rs"]);
+        assert!(ignored(&paths, "src/main.rs", false));
+        assert!(!ignored(&paths, "src/sub/main.rs", false));
+    }
+
+    #[test]
+    fn character_classes() {
+        let ignores = ignores(&["log[0-9]", "tmp[!a-c]", "x[ab]"]);
+        assert!(ignored(&ignores, "log7", false));
+        assert!(!ignored(&ignores, "logx", false));
+        assert!(ignored(&ignores, "tmpd", false));
+        assert!(!ignored(&ignores, "tmpb", false));
+        assert!(ignored(&ignores, "xa", false));
+        assert!(!ignored(&ignores, "xc", false));
+    }
+
+    #[test]
+    fn inside_ignored_directories() {
+        let ignores = ignores(&["node_modules/", "cache"]);
+        assert!(ignored(&ignores, "node_modules/pkg/index.js", false));
+        assert!(ignored(&ignores, "web/node_modules/pkg", true));
+        assert!(ignored(&ignores, "a/cache/b/c", false));
+        assert!(!ignored(&ignores, "a/cached/b", false));
+    }
+
+    #[test]
+    fn scoped_to_base() {
+        let ignores = ignores(&["*.log"]);
+        assert!(!ignores.matches(Path::new("/elsewhere/a.log"), false));
+        assert!(ignores.matches(Path::new("/root/a.log"), false));
+    }
+}
@@ -0,0 +1,228 @@
+use std::env;
+use std::borrow::Cow;
+use std::path::Path;
+use std::convert;
+use isatty;
+use shell_escape;
+use process;
+use exec;
+use rsync;
+use errors::SlinkResult;
+use remote::KubectlRemote;
+use transport::{self, ControlMaster, Transport};
+
+/*
+ * The kubectl transport: kubectl exec stands in for ssh, and kubectl cp for
+ * scp. kubectl itself runs either locally or on another remote.
+ */
+pub struct Kubectl {
+    name: String,
+    remote: KubectlRemote,
+    via: Option<Box<dyn Transport>>,
+}
+
+impl Kubectl {
+    pub fn new(name: &str, remote: &KubectlRemote, via: Option<Box<dyn Transport>>) -> Kubectl {
+        Kubectl {
+            name: name.to_string(),
+            remote: remote.clone(),
+            via: via,
+        }
+    }
+
+    // Run kubectl with the given arguments, wherever it's supposed to run
+    fn kubectl(&self, args: Vec<String>, tty: bool) -> SlinkResult<()> {
+        match self.via {
+            Some(ref via) => via.command(exec::shell_join("kubectl", &args).as_str(), tty),
+            None => {
+                try!(process::run("kubectl", |cmd| {
+                    cmd.args(&args);
+                }));
+                Ok(())
+            },
+        }
+    }
+
+    fn kubectl_output(&self, args: Vec<String>) -> SlinkResult<String> {
+        match self.via {
+            Some(ref via) => via.output(exec::shell_join("kubectl", &args).as_str()),
+            None => {
+                let output = try!(process::output("kubectl", |cmd| {
+                    cmd.args(&args);
+                }));
+                Ok(output)
+            },
+        }
+    }
+
+    // Arguments for kubectl exec to run a shell command in the pod
+    fn exec_args(&self, pod: String, command: &str, tty: bool) -> Vec<String> {
+        let mut args = self.kubectl_opts();
+        args.push(String::from("exec"));
+
+        // Always pass stdin through; allocate a PTY for interactivity if
+        // stdout is a tty
+        args.push(String::from("-i"));
+        if tty && isatty::stdout_isatty() {
+            args.push(String::from("-t"));
+        }
+
+        args.extend(self.container_opts());
+        args.push(pod);
+
+        // Unlike ssh, kubectl exec doesn't run its command in a shell
+        args.push(String::from("--"));
+        args.push(String::from("sh"));
+        args.push(String::from("-c"));
+        args.push(command.to_string());
+
+        args
+    }
+
+    // Options selecting the cluster and namespace, common to every command
+    fn kubectl_opts(&self) -> Vec<String> {
+        let mut vec = Vec::new();
+
+        if let Some(ref context) = self.remote.context {
+            vec.push(format!("--context={}", context));
+        }
+        if let Some(ref namespace) = self.remote.namespace {
+            vec.push(format!("--namespace={}", namespace));
+        }
+
+        vec
+    }
+
+    fn container_opts(&self) -> Vec<String> {
+        match self.remote.container {
+            Some(ref container) => vec![format!("--container={}", container)],
+            None => Vec::new(),
+        }
+    }
+
+    // The pod to connect to, looking it up by selector if necessary
+    fn pod(&self) -> SlinkResult<String> {
+        if let Some(ref pod) = self.remote.pod {
+            return Ok(pod.clone());
+        }
+
+        let selector = self.remote.selector.clone().unwrap_or(String::new());
+        let mut args = self.kubectl_opts();
+        args.push(String::from("get"));
+        args.push(String::from("pods"));
+        args.push(format!("--selector={}", selector));
+        args.push(String::from("--field-selector=status.phase=Running"));
+        args.push(String::from("--output=jsonpath={.items[0].metadata.name}"));
+        let output = try!(self.kubectl_output(args));
+
+        let pod = output.trim();
+        if pod == "" {
+            return Err(convert::From::from(transport::Error::NoMatchingPod(selector)));
+        }
+
+        Ok(pod.to_string())
+    }
+
+    fn rsync_path(&self, path: &str) -> String {
+        format!("{}:{}", self.rsync_host(), path)
+    }
+
+    fn cp(&self, from: String, to: String) -> SlinkResult<()> {
+        let mut args = self.kubectl_opts();
+        args.push(String::from("cp"));
+        args.extend(self.container_opts());
+        args.push(from);
+        args.push(to);
+        self.kubectl(args, false)
+    }
+}
+
+impl Transport for Kubectl {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn command(&self, command: &str, tty: bool) -> SlinkResult<()> {
+        let pod = try!(self.pod());
+
+        self.kubectl(self.exec_args(pod, command, tty), tty)
+    }
+
+    fn output(&self, command: &str) -> SlinkResult<String> {
+        let pod = try!(self.pod());
+        self.kubectl_output(self.exec_args(pod, command, false))
+    }
+
+    // Only the remote kubectl runs on has a connection that can time out
+    fn probe(&self, command: &str) -> SlinkResult<String> {
+        let pod = try!(self.pod());
+        let args = self.exec_args(pod, command, false);
+        match self.via {
+            Some(ref via) => via.probe(exec::shell_join("kubectl", &args).as_str()),
+            None => self.kubectl_output(args),
+        }
+    }
+
+    /*
+     * kubectl cp only works with files local to kubectl, so when kubectl runs
+     * on another remote, copy with rsync through this transport instead.
+     */
+    fn upload(&self, from: &Path, to: &Path) -> SlinkResult<()> {
+        let to_str = to.to_str().unwrap();
+        if self.via.is_some() {
+            return rsync::copy(self, from.to_str().unwrap(), &self.rsync_path(to_str));
+        }
+
+        let pod = try!(self.pod());
+        self.cp(from.to_str().unwrap().to_string(), format!("{}:{}", pod, to_str))
+    }
+
+    fn download(&self, from: &Path, to: &Path) -> SlinkResult<()> {
+        let from_str = from.to_str().unwrap();
+        if self.via.is_some() {
+            return rsync::copy(self, &self.rsync_path(from_str), to.to_str().unwrap());
+        }
+
+        let pod = try!(self.pod());
+        self.cp(format!("{}:{}", pod, from_str), to.to_str().unwrap().to_string())
+    }
+
+    /*
+     * rsync runs its remote shell as `<shell> <host> rsync --server ...`, but
+     * kubectl exec needs a -- before the command, and the pod may need to be
+     * looked up first. So point rsync at slink itself, with the remote name as
+     * the host, and let slink run the command through this transport.
+     */
+    fn rsync_shell(&self) -> String {
+        let slink = env::current_exe().unwrap();
+        let slink_str = slink.to_str().unwrap();
+        format!("{} rsh", shell_escape::escape(Cow::Borrowed(slink_str)))
+    }
+
+    fn rsync_host(&self) -> String {
+        self.name.clone()
+    }
+
+    // kubectl doesn't share connections itself, but the remote it runs on
+    // might
+    fn control_master(&self) -> ControlMaster {
+        match self.via {
+            Some(ref via) => via.control_master(),
+            None => ControlMaster::Unused,
+        }
+    }
+
+    fn connect(&self, hold: bool) -> SlinkResult<()> {
+        match self.via {
+            Some(ref via) => via.connect(hold),
+            None => Err(From::from(transport::unsupported(self.name(), "shared connections"))),
+        }
+    }
+
+    fn disconnect(&self) -> SlinkResult<()> {
+        match self.via {
+            Some(ref via) => via.disconnect(),
+            None => Err(From::from(transport::unsupported(self.name(), "shared connections"))),
+        }
+    }
+}
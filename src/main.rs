@@ -1,9 +1,18 @@
 #[macro_use]
 extern crate structopt;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate serde_yaml;
+extern crate serde_json;
 extern crate xdg;
 extern crate pathdiff;
 extern crate shell_escape;
 extern crate isatty;
+extern crate notify;
+extern crate chrono;
+extern crate md5;
+extern crate libc;
 
 mod cli;
 mod conn;
@@ -13,86 +22,583 @@ mod paths;
 mod exec;
 mod rsync;
 mod config;
+mod remote;
+mod watch;
+mod transport;
+mod kubectl;
+mod ignore;
+mod bisync;
+mod backup;
+mod progress;
+mod output;
+mod status;
+mod forward;
+mod detect;
+mod relay;
 
 use structopt::StructOpt;
 use std::path::PathBuf;
 use std::vec::Vec;
-use cli::{SlinkCommand, RsyncDirection};
-use errors::SlinkResult;
+use std::io::{self, Write};
+use cli::{Slink, SlinkCommand, RsyncDirection, RemoteCommand, AddRemote, ForwardCommand};
+use config::{CommandKind, ConflictPolicy};
+use errors::{SlinkError, SlinkResult};
+use forward::Forward;
+use ignore::Direction;
+use progress::Verbosity;
+use transport::{ControlMaster, Transport};
+use remote::{Remote, RemoteKind, Remotes, SshRemote, KubectlRemote, ForwardRemote};
 
 fn main() {
-    let result = match SlinkCommand::from_args() {
+    let args = Slink::from_args();
+    output::set_json(args.json);
+    let command = args.command;
+
+    // These commands stand in for a command run on the remote, so should exit
+    // the same way it did
+    let pass_through = match command {
+        SlinkCommand::Go |
+        SlinkCommand::Run { .. } |
+        SlinkCommand::Rsh { .. } |
+        SlinkCommand::Proxy { .. } => true,
+        _ => false,
+    };
+
+    let result = match command {
         SlinkCommand::Use { host } => use_host(host),
+        SlinkCommand::Remote { command } => {
+            match command {
+                RemoteCommand::Add { remote } => remote_add(remote),
+                RemoteCommand::Remove { name } => remote_remove(name),
+                RemoteCommand::List => remote_list(),
+                RemoteCommand::Rename { old, new } => remote_rename(old, new),
+                RemoteCommand::Use { name } => remote_use(name),
+            }
+        },
         SlinkCommand::Current => current(),
-        SlinkCommand::Go => go(),
-        SlinkCommand::Run { command } => run(command),
-        SlinkCommand::Forward { ports } => forward(ports),
-        SlinkCommand::Rsync { direction } => {
-            match direction {
-                RsyncDirection::Up => rsync_up(),
-                RsyncDirection::Down => rsync_down(),
+        SlinkCommand::Status => status::status(),
+        SlinkCommand::Connect { hold } => connect(hold),
+        SlinkCommand::Disconnect => disconnect(),
+        SlinkCommand::Reset => reset(),
+        SlinkCommand::History => history(),
+        SlinkCommand::Clear => clear(),
+        SlinkCommand::Go => with_transport(CommandKind::Go, go),
+        SlinkCommand::Run { command } => {
+            with_transport(CommandKind::Run, |transport| run(transport, command))
+        },
+        SlinkCommand::Forward { command: Some(command), .. } => {
+            match command {
+                ForwardCommand::List => forward_list(),
+                ForwardCommand::Stop { ports } => forward_stop(ports),
             }
         },
-        SlinkCommand::Upload { path } => upload(path),
-        SlinkCommand::Download { path } => download(path),
+        SlinkCommand::Forward { detach, ports, reverse, socks, command: None } => {
+            let mut forwards = ports;
+            forwards.extend(reverse);
+            forwards.extend(socks);
+            with_transport(CommandKind::Forward, |transport| {
+                if detach {
+                    forward_detach(transport, forwards)
+                } else {
+                    forward(transport, forwards)
+                }
+            })
+        },
+        SlinkCommand::Rsync { direction } => {
+            with_transport(CommandKind::Sync, |transport| {
+                match direction {
+                    RsyncDirection::Up { watch, dry_run, confirm, quiet, verbose, remote_path } => {
+                        let verbosity = verbosity(quiet, verbose);
+                        rsync_up(transport, watch, dry_run, confirm, verbosity, remote_path)
+                    },
+                    RsyncDirection::Down { dry_run, confirm, quiet, verbose, remote_path } => {
+                        let verbosity = verbosity(quiet, verbose);
+                        rsync_down(transport, dry_run, confirm, verbosity, remote_path)
+                    },
+                    RsyncDirection::Undo => backup::undo(transport),
+                    RsyncDirection::Both { conflicts, remote_path } => {
+                        rsync_both(transport, conflicts, remote_path)
+                    },
+                }
+            })
+        },
+        SlinkCommand::Upload { path, remote_path } => {
+            with_transport(CommandKind::Upload, |transport| {
+                upload(transport, path, remote_path)
+            })
+        },
+        SlinkCommand::Download { path, remote_path } => {
+            with_transport(CommandKind::Download, |transport| {
+                download(transport, path, remote_path)
+            })
+        },
         SlinkCommand::Debug => debug(),
+        SlinkCommand::Rsh { remote, command } => rsh(remote, command),
+        SlinkCommand::Proxy { via, host, port } => proxy(via, host, port),
+        SlinkCommand::Relay { background, relays } => relay::serve(&relays, background),
     };
 
     match result {
         Ok(_) => {},
-        Err(e) => errors::log_error_and_exit(e),
+        Err(e) => {
+            if pass_through {
+                errors::pass_through_exit(e)
+            } else {
+                errors::log_error_and_exit(e)
+            }
+        },
     };
 }
 
+// Resolve the remote a command should use, and run the command against it
+fn with_transport<F>(command: CommandKind, f: F) -> SlinkResult<()>
+    where F: FnOnce(&dyn Transport) -> SlinkResult<()>
+{
+    let transport = try!(transport::for_command(command));
+    f(&*transport)
+}
+
 fn use_host(host: String) -> SlinkResult<()> {
-    println!("Using host: {}", host);
+    output::info(&format!("Using host: {}", host));
     config::set_host(host.as_str())
 }
 
 fn current() -> SlinkResult<()> {
-    println!("{}", try!(config::get_host()));
+    let remote = try!(config::get_remote());
+    if output::json() {
+        output::print_json(&remote.summary(true));
+    } else if remote.name == remote.to_string() {
+        println!("{}", remote);
+    } else {
+        println!("{}: {}", remote.name, remote);
+    }
+    Ok(())
+}
+
+fn connect(hold: bool) -> SlinkResult<()> {
+    let remotes = try!(Remotes::load());
+    let remote = try!(config::get_remote());
+    let transport = try!(transport::for_remote(&remotes, &remote));
+    try!(transport.connect(hold));
+
+    match transport.control_master() {
+        ControlMaster::Held => output::info(&format!(
+            "Connected to {}; run slink disconnect to close the connection", remote.name
+        )),
+        ControlMaster::Running(persist) => output::info(&format!(
+            "Connected to {}; the connection closes {} after its last use", remote.name, persist
+        )),
+        _ => output::info(&format!("Connected to {}", remote.name)),
+    }
+    Ok(())
+}
+
+fn disconnect() -> SlinkResult<()> {
+    let remotes = try!(Remotes::load());
+    let remote = try!(config::get_remote());
+    let transport = try!(transport::for_remote(&remotes, &remote));
+    try!(transport.disconnect());
+    output::info(&format!("Disconnected from {}", remote.name));
+    Ok(())
+}
+
+fn reset() -> SlinkResult<()> {
+    let mut remotes = try!(Remotes::load());
+    match remotes.reset() {
+        Some(name) => output::info(&format!("Using remote: {}", name)),
+        None => output::info("No remote in use; run slink use <host> to set one"),
+    };
+    remotes.save()
+}
+
+fn history() -> SlinkResult<()> {
+    let remotes = try!(Remotes::load());
+    if output::json() {
+        output::print_json(&remotes.history());
+        return Ok(());
+    }
+
+    for (i, name) in remotes.history().iter().enumerate() {
+        let marker = if i == 0 { "*" } else { " " };
+        println!("{} {}", marker, name);
+    }
+    Ok(())
+}
+
+fn clear() -> SlinkResult<()> {
+    // Close connections first, so none are left running without a socket
+    for socket in conn::control_sockets() {
+        conn::exit_master(socket.as_path());
+    }
+    remote::clear()
+}
+
+fn remote_add(add: AddRemote) -> SlinkResult<()> {
+    let remote = match add {
+        AddRemote::Ssh { name, hostname, user, port, identity_file, persist } => {
+            Remote {
+                name: name,
+                kind: RemoteKind::Ssh(SshRemote {
+                    hostname: hostname,
+                    user: user,
+                    port: port,
+                    identity_file: identity_file,
+                    persist: persist,
+                }),
+            }
+        },
+        AddRemote::Kubectl { name, pod, selector, namespace, container, context, via } => {
+            Remote {
+                name: name,
+                kind: RemoteKind::Kubectl(KubectlRemote {
+                    pod: pod,
+                    selector: selector,
+                    namespace: namespace,
+                    container: container,
+                    context: context,
+                    via: via,
+                }),
+            }
+        },
+        AddRemote::Forward { name, via, port, hostname, user, identity_file, persist } => {
+            Remote {
+                name: name,
+                kind: RemoteKind::Forward(ForwardRemote {
+                    via: via,
+                    hostname: hostname,
+                    port: port,
+                    user: user,
+                    identity_file: identity_file,
+                    persist: persist,
+                }),
+            }
+        },
+    };
+
+    let mut remotes = try!(Remotes::load());
+
+    // Make sure any via remotes exist and don't lead back to this one before
+    // saving it. Nothing connects, so a remote that's down can still be added.
+    try!(transport::for_remote(&remotes, &remote));
+    let message = format!("Added remote {}: {}", remote.name, remote);
+    try!(remotes.add(remote));
+    try!(remotes.save());
+    output::info(&message);
+    Ok(())
+}
+
+fn remote_remove(name: String) -> SlinkResult<()> {
+    let mut remotes = try!(Remotes::load());
+    try!(remotes.remove(name.as_str()));
+    output::info(&format!("Removed remote {}", name));
+    remotes.save()
+}
+
+fn remote_list() -> SlinkResult<()> {
+    let remotes = try!(Remotes::load());
+    if output::json() {
+        let summaries: Vec<_> = remotes.iter().map(|remote| {
+            remote.summary(remotes.current_name() == Some(remote.name.as_str()))
+        }).collect();
+        output::print_json(&summaries);
+        return Ok(());
+    }
+
+    for remote in remotes.iter() {
+        let marker = if remotes.current_name() == Some(remote.name.as_str()) {
+            "*"
+        } else {
+            " "
+        };
+        println!("{} {}\t{}", marker, remote.name, remote);
+    }
+    Ok(())
+}
+
+fn remote_rename(old: String, new: String) -> SlinkResult<()> {
+    let mut remotes = try!(Remotes::load());
+    try!(remotes.rename(old.as_str(), new.as_str()));
+    output::info(&format!("Renamed remote {} to {}", old, new));
+    remotes.save()
+}
+
+fn remote_use(name: String) -> SlinkResult<()> {
+    let mut remotes = try!(Remotes::load());
+    try!(remotes.set_current(name.as_str()));
+    output::info(&format!("Using remote: {}", name));
+    remotes.save()
+}
+
+fn go(transport: &dyn Transport) -> SlinkResult<()> {
+    let mirror = try!(paths::same_path(transport));
+    let detector = try!(forward_project_ports(transport, CommandKind::Go));
+    let result = transport::remote_exit(transport.command(exec::shell_in(mirror).as_str(), true));
+    if let Some(detector) = detector {
+        detector.stop();
+    }
+
+    match result {
+        // 130 is 128+2, aka SIGINT. A shell reports it when you log out after
+        // a Ctrl-C at its prompt, which isn't an error in an interactive
+        // session.
+        Err(SlinkError::TransportError(transport::Error::RemoteExit(130))) => Ok(()),
+        result => result,
+    }
+}
+
+fn run(transport: &dyn Transport, command: String) -> SlinkResult<()> {
+    let mirror = try!(paths::same_path(transport));
+    let detector = try!(forward_project_ports(transport, CommandKind::Run));
+    let result = transport::remote_exit(transport.command(
+        exec::command_in(mirror, command.as_str()).as_str(),
+        true
+    ));
+    if let Some(detector) = detector {
+        detector.stop();
+    }
+    result
+}
+
+// Make sure the ports the project's .slink/config declares are forwarded, and
+// start forwarding new ones the remote listens on if it asks for that
+fn forward_project_ports(transport: &dyn Transport, command: CommandKind)
+    -> SlinkResult<Option<detect::Detector>>
+{
+    let ports = try!(config::project_config()).ports;
+    let forwards = try!(ports.forwards());
+    if !forwards.is_empty() {
+        try!(forward::ensure(transport, &forwards));
+    }
+
+    if ports.detect() {
+        detect::start(transport, command, &forwards)
+    } else {
+        Ok(None)
+    }
+}
+
+fn forward(transport: &dyn Transport, forwards: Vec<Forward>) -> SlinkResult<()> {
+    let descriptions: Vec<String> = forwards.iter().map(|forward| forward.to_string()).collect();
+    output::info(&format!("Forwarding {}...", descriptions.join(", ")));
+    output::info("Leave this running to keep the ports forwarded.");
+    output::info("<Ctrl-C to exit>");
+    transport.port_forward(&forwards)
+}
+
+fn forward_detach(transport: &dyn Transport, forwards: Vec<Forward>) -> SlinkResult<()> {
+    try!(forward::detach(transport, &forwards));
+    let descriptions: Vec<String> = forwards.iter().map(|forward| forward.to_string()).collect();
+    output::info(&format!("Forwarding {} in the background", descriptions.join(", ")));
+    output::info("The connection stays open until slink disconnect; see slink forward list");
+    Ok(())
+}
+
+fn forward_list() -> SlinkResult<()> {
+    let detached = try!(forward::list());
+    if output::json() {
+        output::print_json(&detached);
+        return Ok(());
+    }
+
+    for detached in detached {
+        println!("{}\t{}", detached.remote, detached.forward);
+    }
+    Ok(())
+}
+
+fn forward_stop(ports: Vec<u16>) -> SlinkResult<()> {
+    for port in ports {
+        for detached in try!(forward::stop(port)) {
+            output::info(&format!("Stopped forwarding {} ({})", detached.forward, detached.remote));
+        }
+    }
     Ok(())
 }
 
-fn go() -> SlinkResult<()> {
-    conn::ssh_command(|ssh| {
-        ssh.arg(exec::shell_in(paths::same_path()));
-    })
+fn verbosity(quiet: bool, verbose: bool) -> Verbosity {
+    // JSON output gets the changes all at once, at the end
+    if quiet || output::json() {
+        Verbosity::Quiet
+    } else if verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    }
 }
 
-fn run(command: String) -> SlinkResult<()> {
-    conn::ssh_command(|ssh| {
-        ssh.arg(exec::command_in(paths::same_path(), command.as_str()));
-    })
+fn rsync_up(transport: &dyn Transport, watch: bool, dry_run: bool, confirm: bool,
+            verbosity: Verbosity, remote_path: Option<PathBuf>)
+    -> SlinkResult<()>
+{
+    let to = try!(remote_or_mirror(transport, remote_path));
+
+    if dry_run {
+        print_changes(try!(rsync::up_dry_run(transport, to)));
+        return Ok(());
+    }
+    if confirm && !try!(confirm_sync(try!(rsync::up_dry_run(transport, to.clone())))) {
+        return Ok(());
+    }
+
+    if watch {
+        watch::up(transport, to)
+    } else {
+        let changes = try!(rsync::up(transport, to, verbosity));
+        if output::json() {
+            output::print_json(&changes);
+        }
+        Ok(())
+    }
 }
 
-fn forward(ports: Vec<String>) -> SlinkResult<()> {
-    println!("Forwarding {}...", ports.join(", "));
-    println!("Leave this running to keep the ports forwarded.");
-    println!("<Ctrl-C to exit>");
-    conn::port_forward(ports)
+fn rsync_down(transport: &dyn Transport, dry_run: bool, confirm: bool,
+              verbosity: Verbosity, remote_path: Option<PathBuf>)
+    -> SlinkResult<()>
+{
+    let from = try!(remote_or_mirror(transport, remote_path));
+
+    if dry_run {
+        print_changes(try!(rsync::down_dry_run(transport, from)));
+        return Ok(());
+    }
+    if confirm && !try!(confirm_sync(try!(rsync::down_dry_run(transport, from.clone())))) {
+        return Ok(());
+    }
+
+    let changes = try!(rsync::down(transport, from, verbosity));
+    if output::json() {
+        output::print_json(&changes);
+    }
+    Ok(())
 }
 
-fn rsync_up() -> SlinkResult<()> {
-    rsync::up(paths::same_path())
+fn rsync_both(transport: &dyn Transport, conflicts: Option<ConflictPolicy>,
+              remote_path: Option<PathBuf>)
+    -> SlinkResult<()>
+{
+    let policy = match conflicts {
+        Some(policy) => Some(policy),
+        None => try!(config::project_config()).sync.conflicts(),
+    };
+    bisync::sync(transport, try!(remote_or_mirror(transport, remote_path)), policy)
 }
 
-fn rsync_down() -> SlinkResult<()> {
-    rsync::down(paths::same_path())
+fn print_changes(changes: rsync::Changes) {
+    if output::json() {
+        output::print_json(&changes);
+    } else {
+        changes.print();
+    }
 }
 
-fn upload(path: PathBuf) -> SlinkResult<()> {
-    let to = paths::same_path().join(path.canonicalize().unwrap().as_path());
-    conn::scp_up(path, to)
+// If a sync would delete more files than the project allows without asking,
+// show what it would change and ask whether to go ahead
+fn confirm_sync(changes: rsync::Changes) -> SlinkResult<bool> {
+    let threshold = try!(config::project_config()).sync.confirm_threshold();
+    if changes.deleted.len() <= threshold {
+        return Ok(true);
+    }
+
+    // Keep stdout for the sync's JSON
+    if output::json() {
+        eprint!("The sync would delete {} files. Continue? [y/N] ", changes.deleted.len());
+    } else {
+        changes.print();
+        print!("Continue? [y/N] ");
+        let _ = io::stdout().flush();
+    }
+
+    let mut answer = String::new();
+    let _ = io::stdin().read_line(&mut answer);
+    let confirmed = answer.trim().to_lowercase().starts_with('y');
+
+    if !confirmed {
+        eprintln!("Not syncing");
+    }
+    Ok(confirmed)
+}
+
+fn upload(transport: &dyn Transport, path: PathBuf, remote_path: Option<PathBuf>)
+    -> SlinkResult<()>
+{
+    let to = match remote_path {
+        Some(remote_path) => try!(paths::resolve_remote(transport, remote_path.as_path())),
+        None => try!(paths::remote_path(transport, path.canonicalize().unwrap().as_path())),
+    };
+    transport.upload(path.as_path(), to.as_path())
 }
 
-fn download(path: PathBuf) -> SlinkResult<()> {
-    let from = paths::same_path().join(path.as_path());
-    conn::scp_down(from, path)
+fn download(transport: &dyn Transport, path: PathBuf, remote_path: Option<PathBuf>)
+    -> SlinkResult<()>
+{
+    let remote_path = remote_path.as_ref().unwrap_or(&path);
+    let from = try!(paths::resolve_remote(transport, remote_path.as_path()));
+    transport.download(from.as_path(), path.as_path())
+}
+
+// The remote path given on the command line, or the mirror of the PWD
+fn remote_or_mirror(transport: &dyn Transport, remote_path: Option<PathBuf>)
+    -> SlinkResult<PathBuf>
+{
+    match remote_path {
+        Some(remote_path) => paths::resolve_remote(transport, remote_path.as_path()),
+        None => paths::same_path(transport),
+    }
+}
+
+#[derive(Serialize)]
+struct DebugInfo {
+    git_tracked: bool,
+    patterns: Vec<String>,
+    ignored: Vec<ignore::Entry>,
 }
 
 fn debug() -> SlinkResult<()> {
-    let ignored = config::ignored_files();
-    println!("ignored files: {:?}", ignored);
+    // Walking picks up nested ignore files, so do it before listing patterns
+    let settings = try!(config::project_config()).sync;
+    let mut ignores = config::ignores(&settings, Direction::Up);
+    let ignored = ignores.walk(paths::pwd_or_panic().as_path());
+
+    if output::json() {
+        output::print_json(&DebugInfo {
+            git_tracked: settings.git_tracked(),
+            patterns: ignores.describe(),
+            ignored: ignored,
+        });
+        return Ok(());
+    }
+
+    if settings.git_tracked() {
+        println!("only files tracked by git are synced");
+    }
+
+    println!("ignore patterns:");
+    for pattern in ignores.describe() {
+        println!("  {}", pattern);
+    }
+
+    println!("ignored paths:");
+    for entry in ignored {
+        println!("  {}{}", entry.path.display(), if entry.is_dir { "/" } else { "" });
+    }
     Ok(())
 }
+
+// rsync invokes its remote shell as `<shell> <host> <command...>`; the host is
+// the remote's name, and the command needs to be run through its transport with
+// stdio passed straight through
+fn rsh(name: String, command: Vec<String>) -> SlinkResult<()> {
+    let remotes = try!(Remotes::load());
+    let remote = try!(remotes.get(name.as_str()));
+    let transport = try!(transport::for_remote(&remotes, &remote));
+    transport::remote_exit(transport.command(command.join(" ").as_str(), false))
+}
+
+// ssh invokes its ProxyCommand to get a connection to host:port, which is
+// provided by the via remote's transport
+fn proxy(via: String, host: String, port: u16) -> SlinkResult<()> {
+    let remotes = try!(Remotes::load());
+    let remote = try!(remotes.get(via.as_str()));
+    let transport = try!(transport::for_remote(&remotes, &remote));
+    transport::remote_exit(transport.proxy(host.as_str(), port))
+}
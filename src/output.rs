@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use serde::Serialize;
+use serde_json;
+
+// Set once from the command line, before anything is printed
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::SeqCst);
+}
+
+/*
+ * Whether output should be JSON for scripts, rather than text for people
+ */
+pub fn json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+/*
+ * Print a value as a single line of JSON on stdout
+ */
+pub fn print_json<T: Serialize>(value: &T) {
+    match serde_json::to_string(value) {
+        Ok(json) => println!("{}", json),
+        // Everything printed is plain data, so this shouldn't happen
+        Err(e) => eprintln!("Failed to serialize output: {}", e),
+    }
+}
+
+/*
+ * Print a message for people. With JSON output it goes to stderr instead, so
+ * stdout only ever holds JSON.
+ */
+pub fn info(message: &str) {
+    if json() {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
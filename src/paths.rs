@@ -1,14 +1,106 @@
 use std::env;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use pathdiff;
+use errors::SlinkResult;
+use transport::Transport;
 
-pub fn same_path() -> PathBuf {
-    match relative_pwd() {
-        Some(relative_path) => relative_path,
-        None => pwd_or_panic(),
+pub enum Error {
+    // Only the remote user's own home can be looked up
+    UserHome(String),
+    NoRemoteHome,
+}
+
+/*
+ * The path on the remote that mirrors the PWD.
+ */
+pub fn same_path(transport: &dyn Transport) -> SlinkResult<PathBuf> {
+    remote_path(transport, pwd_or_panic().as_path())
+}
+
+/*
+ * The path on the remote that mirrors a local absolute path. By default that's
+ * the same path relative to $HOME if it's in $HOME, or the same absolute path
+ * otherwise. A .slink/target file in the path or one of its parents maps that
+ * directory, and everything under it, to the path in the file instead.
+ */
+pub fn remote_path(transport: &dyn Transport, local: &Path) -> SlinkResult<PathBuf> {
+    let home_dir = env::home_dir();
+
+    for dir in local.ancestors() {
+        // $HOME itself always mirrors $HOME
+        if Some(dir) == home_dir.as_ref().map(|home| home.as_path()) {
+            break;
+        }
+
+        if let Some(target) = read_target(dir) {
+            let target = match try!(expand_home(transport, target.as_str())) {
+                Some(expanded) => expanded,
+                None => PathBuf::from(target),
+            };
+            let rest = local.strip_prefix(dir).unwrap();
+            if rest.as_os_str().is_empty() {
+                return Ok(target);
+            }
+            return Ok(target.join(rest));
+        }
+    }
+
+    let relative = home_dir.and_then(|home_dir| {
+        pathdiff::diff_paths(local, &home_dir)
+    });
+    match relative {
+        Some(ref relative_path) if !relative_path.starts_with("..") => {
+            Ok(relative_path.clone())
+        },
+        _ => Ok(local.to_path_buf()),
+    }
+}
+
+/*
+ * Resolve a path given on the command line to a path on the remote. Absolute
+ * paths are used as-is, paths starting with ~ are in the remote $HOME, and
+ * anything else is relative to the mirror of the PWD.
+ */
+pub fn resolve_remote(transport: &dyn Transport, path: &Path) -> SlinkResult<PathBuf> {
+    if let Some(expanded) = try!(expand_home(transport, path.to_str().unwrap())) {
+        return Ok(expanded);
+    }
+
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(try!(same_path(transport)).join(path))
     }
 }
 
+// Expand a path starting with ~ to one in the remote $HOME. Anything else is
+// left alone, without asking the remote.
+fn expand_home(transport: &dyn Transport, path: &str) -> SlinkResult<Option<PathBuf>> {
+    if path == "~" {
+        return Ok(Some(try!(remote_home(transport))));
+    }
+    if path.starts_with("~/") {
+        return Ok(Some(try!(remote_home(transport)).join(&path[2..])));
+    }
+    if path.starts_with('~') {
+        return Err(Error::UserHome(path.to_string()).into());
+    }
+    Ok(None)
+}
+
+// Ask the remote where its $HOME is, since not every transport starts
+// commands there
+fn remote_home(transport: &dyn Transport) -> SlinkResult<PathBuf> {
+    let output = try!(transport.output("printf '%s' \"$HOME\""));
+    let home = output.trim();
+    if !home.starts_with('/') {
+        return Err(Error::NoRemoteHome.into());
+    }
+    Ok(PathBuf::from(home))
+}
+
 pub fn relative_pwd() -> Option<PathBuf> {
     env::home_dir().and_then(|home_dir| {
         let pwd = pwd_or_panic();
@@ -19,3 +111,24 @@ pub fn relative_pwd() -> Option<PathBuf> {
 pub fn pwd_or_panic() -> PathBuf {
     env::current_dir().unwrap()
 }
+
+// Read the remote path a directory is mapped to, if it has a .slink/target
+// file
+fn read_target(dir: &Path) -> Option<String> {
+    let mut contents = String::new();
+    match File::open(dir.join(".slink/target")) {
+        Err(_) => return None,
+        Ok(mut file) => {
+            if file.read_to_string(&mut contents).is_err() {
+                return None;
+            }
+        },
+    };
+
+    let target = contents.trim();
+    if target == "" {
+        return None;
+    }
+
+    Some(target.to_string())
+}
@@ -1,4 +1,5 @@
-use std::process::Command;
+use std::io::{BufReader, Read};
+use std::process::{Child, Command, Stdio};
 
 pub enum Error<'a> {
     FailedToLaunch(&'a str),
@@ -19,10 +20,123 @@ pub fn run<'a, F>(cmd_str: &'a str, cmd_closure: F) -> Result<(), Error<'a>>
     cmd_closure(&mut command);
 
     // Run and handle errors
+    let child = try!(command.spawn().map_err(|_| {
+        Error::FailedToLaunch(cmd_str)
+    }));
+
+    wait(cmd_str, child)
+}
+
+/*
+ * Like run, but capture the child's stdout and return it rather than letting it
+ * print to the terminal
+ */
+pub fn output<'a, F>(cmd_str: &'a str, cmd_closure: F) -> Result<String, Error<'a>>
+    where F: FnOnce(&mut Command) -> ()
+{
+    output_allowing(cmd_str, &[], cmd_closure)
+}
+
+/*
+ * Like output, but the given exit codes count as success too, returning
+ * whatever the child printed before exiting with them
+ */
+pub fn output_allowing<'a, F>(cmd_str: &'a str, allowed: &[i32], cmd_closure: F) -> Result<String, Error<'a>>
+    where F: FnOnce(&mut Command) -> ()
+{
+    let mut command = Command::new(cmd_str);
+    cmd_closure(&mut command);
+    command.stdout(Stdio::piped());
+
+    let child = try!(command.spawn().map_err(|_| {
+        Error::FailedToLaunch(cmd_str)
+    }));
+
+    let output = try!(child.wait_with_output().map_err(|_| {
+        Error::FailedToWait(cmd_str)
+    }));
+
+    let allowed = output.status.code().map_or(false, |code| allowed.contains(&code));
+    if output.status.success() || allowed {
+        return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+    }
+
+    match output.status.code() {
+        Some(code) => Err(Error::NonZeroExit(cmd_str, code)),
+        None => Err(Error::KilledBySignal(cmd_str)),
+    }
+}
+
+/*
+ * Like run, but capture the child's stdout and pass it to a closure a line at a
+ * time as it's printed. Carriage returns end lines too, so that redrawn
+ * progress output comes through as it changes.
+ */
+pub fn stream<'a, F, G>(cmd_str: &'a str, cmd_closure: F, mut line_closure: G) -> Result<(), Error<'a>>
+    where F: FnOnce(&mut Command) -> (),
+          G: FnMut(&str) -> ()
+{
+    let mut command = Command::new(cmd_str);
+    cmd_closure(&mut command);
+    command.stdout(Stdio::piped());
+
     let mut child = try!(command.spawn().map_err(|_| {
         Error::FailedToLaunch(cmd_str)
     }));
 
+    {
+        let stdout = child.stdout.as_mut().unwrap();
+        let mut line = Vec::new();
+        for byte in BufReader::new(stdout).bytes() {
+            let byte = match byte {
+                Ok(byte) => byte,
+                Err(_) => break,
+            };
+
+            if byte == b'\n' || byte == b'\r' {
+                line_closure(&String::from_utf8_lossy(&line));
+                line.clear();
+            } else {
+                line.push(byte);
+            }
+        }
+        if !line.is_empty() {
+            line_closure(&String::from_utf8_lossy(&line));
+        }
+    }
+
+    let exit_status = try!(child.wait().map_err(|_| {
+        Error::FailedToWait(cmd_str)
+    }));
+
+    if exit_status.success() {
+        return Ok(());
+    }
+
+    match exit_status.code() {
+        Some(code) => Err(Error::NonZeroExit(cmd_str, code)),
+        None => Err(Error::KilledBySignal(cmd_str)),
+    }
+}
+
+/*
+ * Start a configured command as a child process without waiting for it
+ */
+pub fn spawn<'a, F>(cmd_str: &'a str, cmd_closure: F) -> Result<Child, Error<'a>>
+    where F: FnOnce(&mut Command) -> ()
+{
+    let mut command = Command::new(cmd_str);
+    cmd_closure(&mut command);
+
+    command.spawn().map_err(|_| {
+        Error::FailedToLaunch(cmd_str)
+    })
+}
+
+/*
+ * Block until a spawned child process exits, and handle errors
+ */
+pub fn wait<'a>(cmd_str: &'a str, mut child: Child) -> Result<(), Error<'a>> {
     let exit_status = try!(child.wait().map_err(|_| {
         Error::FailedToWait(cmd_str)
     }));
@@ -0,0 +1,127 @@
+use std::io::{self, Write};
+use isatty;
+use rsync::{Change, Changes};
+
+/*
+ * How much a sync prints while it runs
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verbosity {
+    // Nothing but errors
+    Quiet,
+    // A progress line, then a summary
+    Normal,
+    // Each change as well
+    Verbose,
+}
+
+/*
+ * Follows a running sync through rsync's --info=progress2 and
+ * --itemize-changes output, keeping a progress line up to date on a tty
+ */
+pub struct Progress {
+    verbosity: Verbosity,
+    tty: bool,
+    changes: Changes,
+    // Bytes transferred so far, as rsync formats them
+    bytes: String,
+    status: Option<String>,
+}
+
+impl Progress {
+    pub fn new(verbosity: Verbosity) -> Progress {
+        Progress {
+            verbosity: verbosity,
+            tty: isatty::stdout_isatty(),
+            changes: Changes::default(),
+            bytes: String::from("0"),
+            status: None,
+        }
+    }
+
+    /*
+     * Take in a line of rsync's output
+     */
+    pub fn line(&mut self, line: &str) {
+        if let Some(change) = Change::parse(line) {
+            if self.verbosity == Verbosity::Verbose {
+                self.clear();
+                match change {
+                    Change::Create(ref path) => println!("  create  {}", path),
+                    Change::Update(ref path) => println!("  update  {}", path),
+                    Change::Delete(ref path) => println!("  delete  {}", path),
+                }
+                self.draw();
+            }
+            self.changes.add(change);
+            return;
+        }
+
+        // Progress lines look like
+        // "  1,234,567  45%  10.00MB/s  0:00:05 (xfr#3, to-chk=10/20)"
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || !fields[1].ends_with('%') {
+            return;
+        }
+
+        let files = fields.iter()
+            .find(|field| field.starts_with("(xfr#"))
+            .map(|field| field.trim_start_matches("(xfr#").trim_end_matches(','))
+            .unwrap_or("0");
+
+        self.bytes = fields[0].to_string();
+        self.changes.bytes = fields[0].replace(",", "").replace(".", "").parse().ok();
+        self.status = Some(format!(
+            "{:>4}  {} bytes  {}  {} left  {} file{}",
+            fields[1],
+            fields[0],
+            fields[2],
+            fields[3],
+            files,
+            if files == "1" { "" } else { "s" }
+        ));
+        self.draw();
+    }
+
+    /*
+     * Clear the progress line, and summarize the sync if it worked. Returns
+     * everything it changed.
+     */
+    pub fn finish(mut self, succeeded: bool) -> Changes {
+        self.clear();
+        self.status = None;
+        // rsync doesn't report progress when there's nothing to transfer
+        self.changes.bytes = Some(self.changes.bytes.unwrap_or(0));
+
+        if succeeded && self.verbosity != Verbosity::Quiet {
+            println!(
+                "{} created, {} updated, {} deleted; {} bytes transferred",
+                self.changes.created.len(),
+                self.changes.updated.len(),
+                self.changes.deleted.len(),
+                self.bytes
+            );
+        }
+
+        self.changes
+    }
+
+    // The progress line is only drawn on a tty, where it can be redrawn in
+    // place
+    fn draw(&self) {
+        if !self.tty || self.verbosity == Verbosity::Quiet {
+            return;
+        }
+        if let Some(ref status) = self.status {
+            print!("\r\x1B[2K{}", status);
+            let _ = io::stdout().flush();
+        }
+    }
+
+    fn clear(&self) {
+        if self.tty && self.status.is_some() && self.verbosity != Verbosity::Quiet {
+            print!("\r\x1B[2K");
+            let _ = io::stdout().flush();
+        }
+    }
+}
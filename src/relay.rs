@@ -0,0 +1,230 @@
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+use libc;
+use errors::SlinkResult;
+use forward::{self, Forward, DEFAULT_HOST};
+use process;
+
+// How often the relay checks that the ports it relays to are still forwarded
+const CHECK_SECS: u64 = 2;
+
+const IPV6_LOOPBACK: &'static str = "::1";
+
+/*
+ * A privileged local port, relayed to the unprivileged port a forward really
+ * listens on
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct Relay {
+    pub port: u16,
+    pub to: u16,
+}
+
+impl Relay {
+    /*
+     * Parse a relay as the relay command takes it: "port:to"
+     */
+    pub fn parse(spec: &str) -> Result<Relay, String> {
+        let mut parts = spec.splitn(2, ':');
+        let ports = (
+            parts.next().and_then(|port| port.parse().ok()),
+            parts.next().and_then(|to| to.parse().ok()),
+        );
+
+        match ports {
+            (Some(port), Some(to)) => Ok(Relay { port: port, to: to }),
+            _ => Err(format!("invalid relay {}: expected port:to", spec)),
+        }
+    }
+}
+
+impl fmt::Display for Relay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.port, self.to)
+    }
+}
+
+/*
+ * Move privileged forwards onto free unprivileged ports, so that ssh doesn't
+ * need root for them. Returns the forwards to give ssh, and the relays that
+ * bring the moved ones back to the ports asked for.
+ */
+pub fn split(forwards: &[Forward]) -> (Vec<Forward>, Vec<Relay>) {
+    let mut unprivileged = Vec::with_capacity(forwards.len());
+    let mut relays = Vec::new();
+
+    for forward in forwards {
+        if forward.privileged() {
+            let to = free_port();
+            relays.push(Relay { port: forward.port(), to: to });
+            unprivileged.push(forward.with_port(to));
+        } else {
+            unprivileged.push(forward.clone());
+        }
+    }
+
+    (unprivileged, relays)
+}
+
+// A port nothing's listening on, as the OS picks them for outgoing
+// connections. Something else could take it before ssh does, but then ssh
+// fails rather than forwarding the wrong thing.
+fn free_port() -> u16 {
+    let listener = TcpListener::bind((DEFAULT_HOST, 0)).expect("Could not find a free local port");
+    listener.local_addr().expect("Could not find a free local port").port()
+}
+
+/*
+ * Run the relay command with sudo, which may prompt for a password. In the
+ * background, this returns once the relay's listening; otherwise it returns
+ * once the ports relayed to stop being listened on.
+ */
+pub fn start(relays: &[Relay], background: bool) -> SlinkResult<()> {
+    let slink = env::current_exe().unwrap();
+
+    try!(process::run("sudo", |cmd| {
+        cmd.arg(slink);
+        cmd.arg("relay");
+        if background {
+            cmd.arg("--background");
+        }
+        for relay in relays {
+            cmd.arg(relay.to_string());
+        }
+    }));
+
+    Ok(())
+}
+
+/*
+ * Listen on the relays' ports, which needs root, then go back to being the
+ * user sudo was run by. Connections are relayed until a port relayed to stops
+ * being listened on, which happens when the ssh forwarding it exits. In the
+ * background, the relaying carries on in a detached process.
+ */
+pub fn serve(relays: &[Relay], background: bool) -> SlinkResult<()> {
+    let mut listeners = Vec::with_capacity(relays.len());
+    for relay in relays {
+        let listener = try!(TcpListener::bind((DEFAULT_HOST, relay.port)).map_err(|e| {
+            failed_bind(relay, e)
+        }));
+        listeners.push((listener, relay.to));
+
+        // localhost may resolve to either, so listen on IPv6 loopback too
+        // where there is one
+        match TcpListener::bind((IPV6_LOOPBACK, relay.port)) {
+            Ok(listener) => listeners.push((listener, relay.to)),
+            Err(ref e) if no_ipv6(e) => {},
+            Err(e) => return Err(failed_bind(relay, e).into()),
+        }
+    }
+
+    try!(drop_privileges().map_err(forward::Error::FailedRelay));
+    if background {
+        try!(detach().map_err(forward::Error::FailedRelay));
+    }
+
+    for (listener, to) in listeners {
+        thread::spawn(move || accept(listener, to));
+    }
+
+    loop {
+        thread::sleep(Duration::from_secs(CHECK_SECS));
+        if relays.iter().any(|relay| !forward::port_in_use(relay.to)) {
+            return Ok(());
+        }
+    }
+}
+
+fn failed_bind(relay: &Relay, e: io::Error) -> forward::Error {
+    let e = io::Error::new(e.kind(), format!("port {}: {}", relay.port, e));
+    forward::Error::FailedRelay(e)
+}
+
+// Whether binding failed because the machine has no IPv6 loopback
+fn no_ipv6(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::AddrNotAvailable || e.raw_os_error() == Some(libc::EAFNOSUPPORT)
+}
+
+// Switch to the user and group sudo was run by. Supplementary groups are
+// root's, so they go first. Carrying on as root isn't an option, so without
+// sudo's variables this fails.
+fn drop_privileges() -> io::Result<()> {
+    let uid = env::var("SUDO_UID").ok().and_then(|uid| uid.parse::<libc::uid_t>().ok());
+    let gid = env::var("SUDO_GID").ok().and_then(|gid| gid.parse::<libc::gid_t>().ok());
+    let (uid, gid) = match (uid, gid) {
+        (Some(uid), Some(gid)) => (uid, gid),
+        _ => return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "SUDO_UID and SUDO_GID aren't set, so there's no user to switch back to"
+        )),
+    };
+
+    let failed = unsafe {
+        libc::setgroups(0, ptr::null()) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0
+    };
+    if failed {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Carry on in a child process in a session of its own, away from the
+// terminal, and exit the parent so that sudo returns. This has to happen
+// before any threads are started.
+fn detach() -> io::Result<()> {
+    let null = try!(OpenOptions::new().read(true).write(true).open("/dev/null"));
+
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {},
+            _ => libc::_exit(0),
+        }
+
+        libc::setsid();
+        for fd in 0..3 {
+            libc::dup2(null.as_raw_fd(), fd);
+        }
+    }
+
+    Ok(())
+}
+
+fn accept(listener: TcpListener, to: u16) {
+    for stream in listener.incoming() {
+        if let Ok(stream) = stream {
+            thread::spawn(move || pipe(stream, to));
+        }
+    }
+}
+
+// Copy a connection through to the port it's relayed to and back, until both
+// directions are done
+fn pipe(client: TcpStream, to: u16) {
+    let upstream = match TcpStream::connect((DEFAULT_HOST, to)) {
+        Ok(upstream) => upstream,
+        Err(_) => return,
+    };
+    let (mut client_in, mut upstream_out) = match (client.try_clone(), upstream.try_clone()) {
+        (Ok(client_in), Ok(upstream_out)) => (client_in, upstream_out),
+        _ => return,
+    };
+
+    let sending = thread::spawn(move || {
+        let _ = io::copy(&mut client_in, &mut upstream_out);
+        let _ = upstream_out.shutdown(Shutdown::Write);
+    });
+
+    let (mut upstream_in, mut client_out) = (upstream, client);
+    let _ = io::copy(&mut upstream_in, &mut client_out);
+    let _ = client_out.shutdown(Shutdown::Write);
+    let _ = sending.join();
+}
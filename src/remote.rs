@@ -0,0 +1,624 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Values;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use serde_yaml::{self, Value};
+use errors::SlinkResult;
+use config::{Error, xdg_dirs};
+
+const REMOTES_CONFIG_FILE: &'static str = "remotes.yml";
+
+// Before named remotes existed, slink stored a single bare hostname here
+const LEGACY_HOST_CONFIG_FILE: &'static str = "hostname";
+
+// How many remotes the history remembers
+const MAX_HISTORY: usize = 20;
+
+/*
+ * A named remote machine, analogous to a git remote.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Remote {
+    // The shortcut name is the key in the registry, so it isn't serialized
+    // alongside the rest of the remote
+    #[serde(skip)]
+    pub name: String,
+
+    #[serde(flatten)]
+    pub kind: RemoteKind,
+}
+
+/*
+ * How a remote is reached, along with the transport-specific settings.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RemoteKind {
+    Ssh(SshRemote),
+    Kubectl(KubectlRemote),
+    Forward(ForwardRemote),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SshRemote {
+    pub hostname: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<PathBuf>,
+
+    // How long the shared connection stays open after its last use, as an ssh
+    // ControlPersist time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persist: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KubectlRemote {
+    // Exactly one of pod and selector is set; a selector picks the first
+    // matching pod each time slink connects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pod: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+
+    // Another remote to run kubectl on, e.g. when the cluster is only
+    // reachable from a bastion
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+}
+
+/*
+ * An SSH server that's only reachable through another remote, like a jump
+ * host. The other remote may itself be a forward.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForwardRemote {
+    pub via: String,
+
+    // The SSH server's address, as seen from the via remote
+    #[serde(default = "default_forward_hostname")]
+    pub hostname: String,
+
+    pub port: u16,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persist: Option<String>,
+}
+
+fn default_forward_hostname() -> String {
+    String::from("localhost")
+}
+
+/*
+ * Check a --persist time is one ssh's ControlPersist takes: yes, no, or a
+ * number of seconds, optionally as parts like 1h30m
+ */
+pub fn parse_persist(persist: &str) -> Result<String, String> {
+    let valid = match persist {
+        "yes" | "no" => true,
+        "" => false,
+        _ => {
+            // Each unit has to follow a number
+            let mut after_digit = false;
+            persist.chars().all(|c| {
+                if c.is_ascii_digit() {
+                    after_digit = true;
+                    true
+                } else if after_digit && "sSmMhHdDwW".contains(c) {
+                    after_digit = false;
+                    true
+                } else {
+                    false
+                }
+            })
+        },
+    };
+
+    if valid {
+        Ok(persist.to_string())
+    } else {
+        Err(format!("invalid time {}: expected yes, no, or a time like 30s, 10m or 1h30m", persist))
+    }
+}
+
+/*
+ * A remote as --json output describes it: its settings, plus its name and
+ * whether it's the one in use.
+ */
+#[derive(Serialize)]
+pub struct Summary<'a> {
+    name: &'a str,
+    current: bool,
+    description: String,
+
+    #[serde(flatten)]
+    kind: &'a RemoteKind,
+}
+
+impl Remote {
+    pub fn new(name: &str, hostname: &str) -> Remote {
+        Remote {
+            name: name.to_string(),
+            kind: RemoteKind::Ssh(SshRemote {
+                hostname: hostname.to_string(),
+                user: None,
+                port: None,
+                identity_file: None,
+                persist: None,
+            }),
+        }
+    }
+
+    /*
+     * The remote this one is reached through, if any
+     */
+    pub fn via(&self) -> Option<&str> {
+        match self.kind {
+            RemoteKind::Ssh(_) => None,
+            RemoteKind::Kubectl(ref kubectl) => kubectl.via.as_ref().map(|via| via.as_str()),
+            RemoteKind::Forward(ref forward) => Some(forward.via.as_str()),
+        }
+    }
+
+    pub fn summary<'a>(&'a self, current: bool) -> Summary<'a> {
+        Summary {
+            name: self.name.as_str(),
+            current: current,
+            description: self.to_string(),
+            kind: &self.kind,
+        }
+    }
+}
+
+impl ForwardRemote {
+    /*
+     * The SSH connection to make once the via remote has been traversed
+     */
+    pub fn ssh_remote(&self) -> SshRemote {
+        SshRemote {
+            hostname: self.hostname.clone(),
+            user: self.user.clone(),
+            port: Some(self.port),
+            identity_file: self.identity_file.clone(),
+            persist: self.persist.clone(),
+        }
+    }
+}
+
+impl SshRemote {
+    /*
+     * Connection options for this remote. These are passed as -o flags rather
+     * than e.g. -p, since ssh and scp disagree on the short flag names.
+     */
+    pub fn ssh_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        if let Some(ref user) = self.user {
+            args.push(format!("-oUser={}", user));
+        }
+        if let Some(port) = self.port {
+            args.push(format!("-oPort={}", port));
+        }
+        if let Some(ref identity_file) = self.identity_file {
+            args.push(format!("-oIdentityFile={}", identity_file.to_str().unwrap()));
+            // Don't let ssh-agent offer every other key first
+            args.push(String::from("-oIdentitiesOnly=yes"));
+        }
+
+        args
+    }
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            RemoteKind::Ssh(ref ssh) => write!(f, "{}", ssh),
+            RemoteKind::Kubectl(ref kubectl) => write!(f, "{}", kubectl),
+            RemoteKind::Forward(ref forward) => write!(f, "{}", forward),
+        }
+    }
+}
+
+impl fmt::Display for SshRemote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref user) = self.user {
+            try!(write!(f, "{}@", user));
+        }
+        try!(write!(f, "{}", self.hostname));
+        if let Some(port) = self.port {
+            try!(write!(f, ":{}", port));
+        }
+        if let Some(ref identity_file) = self.identity_file {
+            try!(write!(f, " ({})", identity_file.display()));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for KubectlRemote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "kubectl "));
+        if let Some(ref context) = self.context {
+            try!(write!(f, "{}/", context));
+        }
+        if let Some(ref namespace) = self.namespace {
+            try!(write!(f, "{}/", namespace));
+        }
+        match (&self.pod, &self.selector) {
+            (&Some(ref pod), _) => try!(write!(f, "{}", pod)),
+            (&None, &Some(ref selector)) => try!(write!(f, "[{}]", selector)),
+            (&None, &None) => (),
+        }
+        if let Some(ref container) = self.container {
+            try!(write!(f, " ({})", container));
+        }
+        if let Some(ref via) = self.via {
+            try!(write!(f, " via {}", via));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ForwardRemote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref user) = self.user {
+            try!(write!(f, "{}@", user));
+        }
+        try!(write!(f, "{}:{}", self.hostname, self.port));
+        if let Some(ref identity_file) = self.identity_file {
+            try!(write!(f, " ({})", identity_file.display()));
+        }
+        write!(f, " via {}", self.via)
+    }
+}
+
+/*
+ * The set of configured remotes, plus the history of which were used. The last
+ * remote in the history is the one currently in use.
+ */
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Remotes {
+    #[serde(default)]
+    history: Vec<String>,
+
+    // Before the history existed, only the current remote was saved
+    #[serde(default, skip_serializing)]
+    current: Option<String>,
+
+    #[serde(default)]
+    remotes: BTreeMap<String, Remote>,
+}
+
+impl Remotes {
+    /*
+     * Load the remotes registry, migrating the old single-hostname config file
+     * if that's all that exists.
+     */
+    pub fn load() -> SlinkResult<Remotes> {
+        let dirs = xdg_dirs().unwrap();
+
+        let mut remotes = match dirs.find_config_file(REMOTES_CONFIG_FILE) {
+            Some(path) => try!(read_remotes(path)),
+            None => {
+                match dirs.find_config_file(LEGACY_HOST_CONFIG_FILE) {
+                    Some(path) => try!(migrate_legacy_host(path)),
+                    None => Remotes::default(),
+                }
+            },
+        };
+
+        for (name, remote) in remotes.remotes.iter_mut() {
+            remote.name = name.clone();
+        }
+
+        if let Some(current) = remotes.current.take() {
+            if remotes.history.is_empty() {
+                remotes.history.push(current);
+            }
+        }
+        // Histories saved by older versions grew without limit
+        remotes.tidy_history();
+
+        Ok(remotes)
+    }
+
+    pub fn save(&self) -> SlinkResult<()> {
+        let dirs = xdg_dirs().unwrap();
+        let path = dirs.place_config_file(REMOTES_CONFIG_FILE)
+                       .expect("Cannot create config file");
+
+        let file = try!(File::create(path).map_err(|e| {
+            Error::FailedConfigWrite(e)
+        }));
+
+        try!(serde_yaml::to_writer(file, self).map_err(|e| {
+            Error::MalformedConfig(e)
+        }));
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> SlinkResult<Remote> {
+        match self.remotes.get(name) {
+            Some(remote) => Ok(remote.clone()),
+            None => Err(From::from(Error::NoSuchRemote(name.to_string()))),
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.remotes.contains_key(name)
+    }
+
+    /*
+     * The remote commands should run against.
+     */
+    pub fn current(&self) -> SlinkResult<Remote> {
+        match self.current_name() {
+            Some(name) => self.get(name),
+            None => Err(From::from(Error::NoConfigFile)),
+        }
+    }
+
+    pub fn current_name(&self) -> Option<&str> {
+        self.history.last().map(|name| name.as_str())
+    }
+
+    /*
+     * Switch to a remote, remembering the previous one so it can be reset to.
+     */
+    pub fn set_current(&mut self, name: &str) -> SlinkResult<()> {
+        try!(self.get(name));
+        if self.current_name() != Some(name) {
+            self.history.push(name.to_string());
+            self.tidy_history();
+        }
+        Ok(())
+    }
+
+    // Keep only the latest use of each remote, and only the latest uses
+    fn tidy_history(&mut self) {
+        let mut seen = BTreeSet::new();
+        let mut tidied: Vec<String> = self.history.iter().rev()
+            .filter(|name| seen.insert(name.as_str()))
+            .take(MAX_HISTORY)
+            .cloned()
+            .collect();
+        tidied.reverse();
+        self.history = tidied;
+    }
+
+    /*
+     * Switch back to the previously-used remote, returning its name.
+     */
+    pub fn reset(&mut self) -> Option<&str> {
+        self.history.pop();
+        self.current_name()
+    }
+
+    /*
+     * Remote names in the order they were used, most recent first.
+     */
+    pub fn history(&self) -> Vec<&str> {
+        self.history.iter().rev().map(|name| name.as_str()).collect()
+    }
+
+    pub fn add(&mut self, remote: Remote) -> SlinkResult<()> {
+        if self.remotes.contains_key(remote.name.as_str()) {
+            return Err(From::from(Error::RemoteExists(remote.name)));
+        }
+        self.remotes.insert(remote.name.clone(), remote);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> SlinkResult<Remote> {
+        if !self.remotes.contains_key(name) {
+            return Err(From::from(Error::NoSuchRemote(name.to_string())));
+        }
+
+        // Remotes reached through this one would be left pointing at nothing
+        let dependents: Vec<String> = self.remotes.values()
+            .filter(|other| other.via() == Some(name))
+            .map(|other| other.name.clone())
+            .collect();
+        if !dependents.is_empty() {
+            return Err(From::from(Error::RemoteInUse(name.to_string(), dependents)));
+        }
+
+        let remote = self.remotes.remove(name).unwrap();
+
+        self.history.retain(|used| used.as_str() != name);
+        // Removing an entry may leave the same remote twice in a row
+        self.history.dedup();
+
+        Ok(remote)
+    }
+
+    pub fn rename(&mut self, old: &str, new: &str) -> SlinkResult<()> {
+        if self.remotes.contains_key(new) {
+            return Err(From::from(Error::RemoteExists(new.to_string())));
+        }
+
+        let mut remote = match self.remotes.remove(old) {
+            Some(remote) => remote,
+            None => return Err(From::from(Error::NoSuchRemote(old.to_string()))),
+        };
+        remote.name = new.to_string();
+        self.remotes.insert(new.to_string(), remote);
+
+        for used in self.history.iter_mut() {
+            if used.as_str() == old {
+                *used = new.to_string();
+            }
+        }
+
+        // Keep remotes reached through the renamed one pointing at it
+        for other in self.remotes.values_mut() {
+            let via = match other.kind {
+                RemoteKind::Ssh(_) => None,
+                RemoteKind::Kubectl(ref mut kubectl) => kubectl.via.as_mut(),
+                RemoteKind::Forward(ref mut forward) => Some(&mut forward.via),
+            };
+            if let Some(via) = via {
+                if via.as_str() == old {
+                    *via = new.to_string();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn iter<'a>(&'a self) -> Values<'a, String, Remote> {
+        self.remotes.values()
+    }
+}
+
+/*
+ * Delete all remote configuration.
+ */
+pub fn clear() -> SlinkResult<()> {
+    let dirs = xdg_dirs().unwrap();
+
+    for file in &[REMOTES_CONFIG_FILE, LEGACY_HOST_CONFIG_FILE] {
+        if let Some(path) = dirs.find_config_file(file) {
+            try!(fs::remove_file(path).map_err(|e| {
+                Error::FailedConfigWrite(e)
+            }));
+        }
+    }
+
+    Ok(())
+}
+
+fn read_remotes(path: PathBuf) -> SlinkResult<Remotes> {
+    let mut file = try!(File::open(path).map_err(|e| {
+        Error::FailedConfigRead(e)
+    }));
+
+    let mut contents = String::new();
+    try!(file.read_to_string(&mut contents).map_err(|e| {
+        Error::FailedConfigRead(e)
+    }));
+
+    if contents.trim().is_empty() {
+        return Ok(Remotes::default());
+    }
+
+    let mut value: Value = try!(serde_yaml::from_str(contents.as_str()).map_err(|e| {
+        Error::MalformedConfig(e)
+    }));
+    default_to_ssh(&mut value);
+
+    let remotes = try!(serde_yaml::from_value(value).map_err(|e| {
+        Error::MalformedConfig(e)
+    }));
+
+    Ok(remotes)
+}
+
+// Remotes were all SSH before other transports existed, so they were saved
+// without a type. Fill it in for them.
+fn default_to_ssh(value: &mut Value) {
+    let type_key = Value::String(String::from("type"));
+    let remotes = value.as_mapping_mut().and_then(|registry| {
+        registry.get_mut(&Value::String(String::from("remotes")))
+    });
+
+    if let Some(&mut Value::Mapping(ref mut remotes)) = remotes {
+        for (_, remote) in remotes.iter_mut() {
+            if let Value::Mapping(ref mut remote) = *remote {
+                if !remote.contains_key(&type_key) {
+                    remote.insert(type_key.clone(), Value::String(String::from("ssh")));
+                }
+            }
+        }
+    }
+}
+
+// Turn the old one-line hostname file into a registry containing a single
+// remote named after the host, and make it current
+fn migrate_legacy_host(path: PathBuf) -> SlinkResult<Remotes> {
+    let mut file = try!(File::open(path.clone()).map_err(|e| {
+        Error::FailedConfigRead(e)
+    }));
+
+    let mut host = String::new();
+    try!(file.read_to_string(&mut host).map_err(|e| {
+        Error::FailedConfigRead(e)
+    }));
+
+    let mut remotes = Remotes::default();
+    let host = host.trim();
+    if host != "" {
+        try!(remotes.add(Remote::new(host, host)));
+        try!(remotes.set_current(host));
+    }
+
+    try!(remotes.save());
+    try!(fs::remove_file(path).map_err(|e| {
+        Error::FailedConfigWrite(e)
+    }));
+
+    Ok(remotes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_persist, ForwardRemote, Remote, RemoteKind, Remotes};
+
+    #[test]
+    fn persist_times() {
+        for persist in &["yes", "no", "0", "600", "30s", "10m", "1h30m", "2W", "1d12h"] {
+            assert_eq!(parse_persist(persist), Ok(persist.to_string()));
+        }
+        for persist in &["", "m", "10mm", "1.5h", "10 m", "-5", "forever", "10x"] {
+            assert!(parse_persist(persist).is_err(), "{} should be invalid", persist);
+        }
+    }
+
+    #[test]
+    fn remove_via() {
+        let mut remotes = Remotes::default();
+        remotes.add(Remote::new("bastion", "bastion.example.com")).ok().unwrap();
+        remotes.add(Remote {
+            name: String::from("inner"),
+            kind: RemoteKind::Forward(ForwardRemote {
+                via: String::from("bastion"),
+                hostname: String::from("localhost"),
+                port: 2222,
+                user: None,
+                identity_file: None,
+                persist: None,
+            }),
+        }).ok().unwrap();
+
+        assert!(remotes.remove("bastion").is_err());
+        assert!(remotes.remove("inner").is_ok());
+        assert!(remotes.remove("bastion").is_ok());
+        assert!(remotes.remove("bastion").is_err());
+    }
+}
@@ -1,86 +1,760 @@
-use std::process::Command;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use shell_escape;
+use backup::{Snapshot, TRASH_DIR};
+use config::SyncConfig;
 use errors::SlinkResult;
+use ignore::{Direction, Entry};
+use output;
 use process;
-use conn;
+use progress::{Progress, Verbosity};
 use config;
 use paths;
+use transport::Transport;
 
-use pathdiff;
+// Set once an rsync too old to show progress has been warned about
+static WARNED_OLD: AtomicBool = AtomicBool::new(false);
 
-pub fn up(to: PathBuf) -> SlinkResult<()> {
-    let host = try!(config::get_host());
-    let ignored = config::ignored_files();
+/*
+ * Sync the PWD up to the remote, returning what changed there
+ */
+pub fn up(transport: &dyn Transport, to: PathBuf, verbosity: Verbosity) -> SlinkResult<Changes> {
+    let selection = try!(select(transport, &to, true, None));
 
-    rsync(host.as_str(), |cmd| {
+    let result = rsync_progress(transport, verbosity, |cmd| {
         // Use the current directory
         cmd.arg(".");
 
-        // Check all the ignores
-        for ignore in ignored.iter() {
-            // rsync expects all file paths to be relative. If you're looking at
-            // an absolute path, figure out if it's a subdirectory of pwd, and
-            // if so, pass the relative path to rsync's --exclude
-            if ignore.starts_with("/") {
-                let maybe_rel_path = pathdiff::diff_paths(
-                    &PathBuf::from(ignore),
-                    &paths::pwd_or_panic()
-                );
-                match maybe_rel_path {
-                    None => (),
-                    Some(rel_path) => {
-                        if !rel_path.starts_with("../") {
-                            ignore_path(cmd, rel_path);
-                        }
-                    },
-                }
-            }
-            // Otherwise if it's relative, just pass it straight through
-            else {
-                ignore_path(cmd, PathBuf::from(ignore));
-            }
-        }
+        // Leave out everything that's ignored
+        selection.apply(cmd);
 
         // finally, the host:dest string
-        cmd.arg(format!("{}:{}", host, to.to_str().unwrap()));
+        cmd.arg(format!("{}:{}", transport.rsync_host(), to.to_str().unwrap()));
+    });
+
+    selection.remove();
+    let changes = try!(result);
+    try!(selection.finish(transport, &to, &changes.created));
+    Ok(changes)
+}
+
+/*
+ * Like up, but captures rsync's output rather than printing it, and returns the
+ * number of files transferred.
+ */
+pub fn up_counted(transport: &dyn Transport, to: PathBuf) -> SlinkResult<u64> {
+    let selection = try!(select(transport, &to, true, None));
+
+    let result = rsync_output(transport, |cmd| {
+        cmd.arg("--stats");
+        // What's created has to be known to undo it
+        cmd.arg("--itemize-changes");
+        cmd.arg(".");
+        selection.apply(cmd);
+        cmd.arg(format!("{}:{}", transport.rsync_host(), to.to_str().unwrap()));
+    });
+
+    selection.remove();
+    let output = try!(result);
+    try!(selection.finish(transport, &to, &Changes::parse(output.as_str()).created));
+    Ok(transferred_count(output.as_str()))
+}
+
+/*
+ * Work out what up would change on the remote, without changing anything
+ */
+pub fn up_dry_run(transport: &dyn Transport, to: PathBuf) -> SlinkResult<Changes> {
+    let selection = try!(select(transport, &to, true, None));
+
+    let result = rsync_output(transport, |cmd| {
+        cmd.arg("--dry-run");
+        cmd.arg("--itemize-changes");
+        cmd.arg(".");
+        selection.apply(cmd);
+        cmd.arg(format!("{}:{}", transport.rsync_host(), to.to_str().unwrap()));
+    });
+
+    selection.remove();
+    let output = try!(result);
+    Ok(Changes::parse(output.as_str()))
+}
+
+/*
+ * Sync only the given paths, relative to the PWD, up to the remote. Any of the
+ * paths that no longer exist locally are deleted on the remote. Returns the
+ * number of files transferred.
+ */
+pub fn up_paths(transport: &dyn Transport, to: PathBuf, changed: &BTreeSet<PathBuf>) -> SlinkResult<u64> {
+    // Listing the remote on every change would be slow, so only what's
+    // ignored locally is left out here
+    let selection = try!(select(transport, &to, false, Some(changed)));
+
+    let result = rsync_output(transport, |cmd| {
+        cmd.arg("--stats");
+        // What's created has to be known to undo it
+        cmd.arg("--itemize-changes");
+        cmd.arg(".");
+        selection.apply(cmd);
+        cmd.arg(format!("{}:{}", transport.rsync_host(), to.to_str().unwrap()));
+    });
+
+    selection.remove();
+    let output = try!(result);
+    try!(selection.finish(transport, &to, &Changes::parse(output.as_str()).created));
+    Ok(transferred_count(output.as_str()))
+}
+
+// What a sync sends, written out for rsync: a filter file leaving out ignored
+// paths, and optionally a list of the only paths to send. If backups are on,
+// the snapshot the receiving side backs up into.
+struct Selection {
+    filter: PathBuf,
+    files: Option<PathBuf>,
+    backup: Option<Snapshot>,
+}
+
+impl Selection {
+    fn apply(&self, cmd: &mut Command) {
+        cmd.arg(format!("--filter=merge {}", self.filter.to_str().unwrap()));
+
+        if let Some(ref snapshot) = self.backup {
+            snapshot.apply(cmd);
+        }
+
+        if let Some(ref files) = self.files {
+            cmd.arg(format!("--files-from={}", files.to_str().unwrap()));
+            // --files-from turns off recursion, but newly-created directories
+            // need their contents synced too
+            cmd.arg("-r");
+            // Paths in the list that are missing locally were deleted
+            cmd.arg("--delete-missing-args");
+        }
+    }
+
+    fn remove(&self) {
+        let _ = fs::remove_file(&self.filter);
+        if let Some(ref files) = self.files {
+            let _ = fs::remove_file(files);
+        }
+    }
+
+    // Tidy up after a sync that succeeded, given the paths it created
+    fn finish(&self, transport: &dyn Transport, remote_dir: &PathBuf, created: &[String]) -> SlinkResult<()> {
+        match self.backup {
+            Some(ref snapshot) => snapshot.finish(transport, remote_dir, created),
+            None => Ok(()),
+        }
+    }
+}
+
+// Work out what syncing the PWD up should send. Paths ignored locally aren't
+// sent; if list_remote is set, paths ignored in the remote's copy are
+// protected from deletion too. Only the changed paths are sent if given, and
+// only git-tracked files if the project asks for that.
+fn select(transport: &dyn Transport, to: &PathBuf, list_remote: bool, changed: Option<&BTreeSet<PathBuf>>) -> SlinkResult<Selection> {
+    let pwd = paths::pwd_or_panic();
+    let settings = try!(config::project_config()).sync;
+    let mut ignores = config::ignores(&settings, Direction::Up);
+    let excluded = ignores.walk(pwd.as_path());
+
+    let remote_entries = if list_remote {
+        try!(list(transport, to))
+    } else {
+        Vec::new()
+    };
+    let protected = ignores.filter(pwd.as_path(), &remote_entries);
+
+    let files = if settings.git_tracked() {
+        let mut tracked: BTreeSet<PathBuf> = try!(git_tracked()).into_iter().filter(|path| {
+            !ignores.is_ignored(pwd.as_path(), path, false)
+        }).collect();
+
+        // git stops listing files once their deletion is staged, so anything
+        // the remote has that's gone here is sent too, which deletes it there
+        tracked.extend(remote_entries.iter().filter(|entry| {
+            deleted_locally(pwd.as_path(), &entry.path) &&
+                !ignores.is_ignored(pwd.as_path(), &entry.path, entry.is_dir)
+        }).map(|entry| entry.path.clone()));
+
+        match changed {
+            Some(changed) => Some(changed.iter().filter(|path| {
+                tracked.contains(*path) || deleted_locally(pwd.as_path(), path)
+            }).cloned().collect()),
+            None => Some(tracked),
+        }
+    } else {
+        changed.cloned()
+    };
+
+    Ok(Selection {
+        filter: write_filter(&excluded, &protected),
+        files: files.map(|files| write_file_list(&files)),
+        backup: snapshot(&settings, Direction::Up),
     })
 }
 
-fn ignore_path(cmd: &mut Command, rel_path: PathBuf) {
-    cmd.arg("--exclude");
-    cmd.arg(rel_path);
+/*
+ * The files under the PWD that git tracks, relative to it
+ */
+pub fn git_tracked() -> SlinkResult<Vec<PathBuf>> {
+    let output = try!(process::output("git", |cmd| {
+        // -z stops git quoting unusual names
+        cmd.args(&["ls-files", "-z"]);
+    }));
+
+    Ok(output.split('\0')
+             .filter(|path| *path != "")
+             .map(PathBuf::from)
+             .collect())
+}
+
+// Whether a path relative to root is gone locally. Broken symlinks are still
+// there.
+fn deleted_locally(root: &Path, path: &Path) -> bool {
+    fs::symlink_metadata(root.join(path)).is_err()
+}
+
+// rsync reads the list of paths to sync from a file
+fn write_file_list(files: &BTreeSet<PathBuf>) -> PathBuf {
+    let list_path = temp_file("files");
+    let mut list = File::create(list_path.clone())
+                        .expect("Could not write file list");
+    for path in files.iter() {
+        writeln!(list, "{}", path.to_str().unwrap())
+            .expect("Could not write file list");
+    }
+    list_path
+}
+
+// List everything under a directory on the remote, parents first. A directory
+// that doesn't exist yet is empty.
+fn list(transport: &dyn Transport, dir: &PathBuf) -> SlinkResult<Vec<Entry>> {
+    let listing = try!(list_signed(transport, dir));
+    Ok(listing.into_iter().map(|(entry, _)| entry).collect())
+}
+
+/*
+ * The size and modification time of a file, as rsync lists it. After a sync
+ * both copies of a file have the same signature.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub size: u64,
+    pub mtime: String,
+}
+
+// rsync lists modification times in local time, like "2018/01/01 12:00:00"
+pub const MTIME_FORMAT: &'static str = "%Y/%m/%d %H:%M:%S";
+
+// rsync's exit code when source files vanish while it's running
+const VANISHED: i32 = 24;
+
+/*
+ * Like list, but with the signature of each entry. Only a directory that
+ * doesn't exist is empty: if listing one that does fails, an incomplete
+ * listing would look like files had been deleted, so that's an error.
+ */
+pub fn list_signed(transport: &dyn Transport, dir: &PathBuf) -> SlinkResult<Vec<(Entry, Signature)>> {
+    if !try!(remote_dir_exists(transport, dir)) {
+        return Ok(Vec::new());
+    }
+
+    // Files that vanish while rsync lists them don't make the rest of the
+    // listing incomplete
+    let output = try!(process::output_allowing("rsync", &[VANISHED], |cmd| {
+        cmd.arg("-a");
+        cmd.arg("--list-only");
+        cmd.arg("-e");
+        cmd.arg(transport.rsync_shell());
+        cmd.arg(format!("{}:{}/", transport.rsync_host(), dir.to_str().unwrap()));
+    }));
+
+    Ok(output.lines().filter_map(parse_listing).collect())
+}
+
+fn remote_dir_exists(transport: &dyn Transport, dir: &PathBuf) -> SlinkResult<bool> {
+    let output = try!(transport.output(format!(
+        "if test -d {}; then echo exists; fi",
+        shell_escape::escape(Cow::Borrowed(dir.to_str().unwrap()))
+    ).as_str()));
+    Ok(output.trim() == "exists")
+}
+
+// Parse a line of rsync --list-only output, which looks like
+// "drwxr-xr-x          4,096 2018/01/01 12:00:00 some/dir"
+fn parse_listing(line: &str) -> Option<(Entry, Signature)> {
+    let mut rest = line;
+    let mut fields = Vec::new();
+    for _ in 0..4 {
+        rest = rest.trim_start();
+        let end = rest.find(' ').unwrap_or(rest.len());
+        fields.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    let mode = fields[0];
+
+    // Skip anything that isn't a file, like rsync's own messages, checking
+    // it's ASCII before slicing it by byte
+    if mode.len() != 10 || !mode.is_ascii() || !"-dlpscb".contains(&mode[..1])
+        || !rest.starts_with(' ')
+    {
+        return None;
+    }
+
+    let mut name = &rest[1..];
+    if mode.starts_with('l') {
+        if let Some(arrow) = name.find(" -> ") {
+            name = &name[..arrow];
+        }
+    }
+
+    if name == "." {
+        return None;
+    }
+
+    let entry = Entry {
+        path: PathBuf::from(name),
+        is_dir: mode.starts_with('d'),
+    };
+    let signature = Signature {
+        size: fields[1].replace(",", "").parse().unwrap_or(0),
+        mtime: format!("{} {}", fields[2], fields[3]),
+    };
+    Some((entry, signature))
+}
+
+/*
+ * Send exactly the given paths, relative to the PWD, to a directory on the
+ * remote. Paths missing locally are deleted from the remote. Anything deleted
+ * or overwritten is backed up into the snapshot, if there is one.
+ */
+pub fn send(transport: &dyn Transport, to: &PathBuf, paths: &BTreeSet<PathBuf>, backup: Option<&Snapshot>)
+    -> SlinkResult<()>
+{
+    let list_path = write_file_list(paths);
+
+    let result = rsync(transport, |cmd| {
+        cmd.arg(format!("--files-from={}", list_path.to_str().unwrap()));
+        cmd.arg("--delete-missing-args");
+        // Snapshots stay on the side they were made on
+        cmd.arg(format!("--exclude=/{}/", TRASH_DIR));
+        if let Some(snapshot) = backup {
+            snapshot.apply(cmd);
+        }
+        cmd.arg(".");
+        cmd.arg(format!("{}:{}", transport.rsync_host(), to.to_str().unwrap()));
+    });
+
+    let _ = fs::remove_file(list_path);
+    result
+}
+
+/*
+ * Fetch exactly the given paths, relative to a directory on the remote, to
+ * the PWD. Paths missing from the remote are deleted locally. Like send, this
+ * backs up into the snapshot if there is one.
+ */
+pub fn fetch(transport: &dyn Transport, from: &PathBuf, paths: &BTreeSet<PathBuf>, backup: Option<&Snapshot>)
+    -> SlinkResult<()>
+{
+    let list_path = write_file_list(paths);
+
+    let result = rsync(transport, |cmd| {
+        cmd.arg(format!("--files-from={}", list_path.to_str().unwrap()));
+        cmd.arg("--delete-missing-args");
+        // Snapshots stay on the side they were made on
+        cmd.arg(format!("--exclude=/{}/", TRASH_DIR));
+        if let Some(snapshot) = backup {
+            snapshot.apply(cmd);
+        }
+        cmd.arg(format!("{}:{}/", transport.rsync_host(), from.to_str().unwrap()));
+        cmd.arg(".");
+    });
+
+    let _ = fs::remove_file(list_path);
+    result
+}
+
+// Write rsync filter rules leaving out exactly the given paths: excluded ones
+// aren't sent (or deleted), and protected ones aren't deleted from the receiver
+fn write_filter(excluded: &[Entry], protected: &[Entry]) -> PathBuf {
+    let path = temp_file("filter");
+    let mut file = File::create(path.clone())
+                        .expect("Could not write filter file");
+
+    // Backups are never synced, or deleted by a sync
+    writeln!(file, "- /{}/", TRASH_DIR).expect("Could not write filter file");
+
+    for entry in excluded.iter() {
+        writeln!(file, "- {}", filter_pattern(entry))
+            .expect("Could not write filter file");
+    }
+    for entry in protected.iter() {
+        writeln!(file, "P {}", filter_pattern(entry))
+            .expect("Could not write filter file");
+    }
+
+    path
+}
+
+// An rsync pattern matching just this path from the root of the transfer
+fn filter_pattern(entry: &Entry) -> String {
+    let mut pattern = format!("/{}", entry.path.to_str().unwrap());
+
+    // rsync only treats backslashes as escapes in patterns with wildcards
+    if pattern.contains(|c| c == '*' || c == '?' || c == '[') {
+        pattern = pattern.chars().fold(String::new(), |mut escaped, c| {
+            if c == '*' || c == '?' || c == '[' || c == '\\' {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+            escaped
+        });
+    }
+
+    if entry.is_dir {
+        pattern.push('/');
+    }
+    pattern
+}
+
+// A file in the cache dir that's unique to this process, so concurrent syncs
+// don't trample each other
+fn temp_file(name: &str) -> PathBuf {
+    let dirs = config::xdg_dirs().unwrap();
+    dirs.place_cache_file(format!("{}-{}", name, ::std::process::id()))
+        .expect("Could not create temporary file")
+}
+
+/*
+ * The paths a sync changes on the receiving side
+ */
+#[derive(Serialize, Debug, Default)]
+pub struct Changes {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub deleted: Vec<String>,
+    // Only known once a sync has actually run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+/*
+ * A single change rsync itemized
+ */
+pub enum Change {
+    Create(String),
+    Update(String),
+    Delete(String),
 }
 
-pub fn down(from: PathBuf) -> SlinkResult<()> {
-    let host = try!(config::get_host());
+impl Change {
+    /*
+     * Parse a line of rsync's --itemize-changes output, if it's a change
+     */
+    pub fn parse(line: &str) -> Option<Change> {
+        if line.starts_with("*deleting") {
+            return Some(Change::Delete(line["*deleting".len()..].trim_start().to_string()));
+        }
+
+        // Itemized lines look like ">f.st...... path": what's happening, the
+        // file type, then which attributes changed. Anything starting with "."
+        // isn't being transferred.
+        let flags = match line.get(..11) {
+            Some(flags) if flags.is_ascii() && line[11..].starts_with(' ') => flags,
+            _ => return None,
+        };
+        if !"<>ch".contains(&flags[..1]) {
+            return None;
+        }
 
-    rsync(host.as_str(), |cmd| {
-        // the host:dest string
-        cmd.arg(format!("{}:{}/**", host, from.to_str().unwrap()));
+        let mut path = &line[12..];
+        if let Some(arrow) = path.find(" -> ") {
+            path = &path[..arrow];
+        }
+
+        if flags[2..].chars().all(|c| c == '+') {
+            Some(Change::Create(path.to_string()))
+        } else {
+            Some(Change::Update(path.to_string()))
+        }
+    }
+}
+
+impl Changes {
+    /*
+     * Collect the changes from rsync's --itemize-changes output
+     */
+    pub fn parse(output: &str) -> Changes {
+        let mut changes = Changes::default();
+        for change in output.lines().filter_map(Change::parse) {
+            changes.add(change);
+        }
+        changes
+    }
+
+    pub fn add(&mut self, change: Change) {
+        match change {
+            Change::Create(path) => self.created.push(path),
+            Change::Update(path) => self.updated.push(path),
+            Change::Delete(path) => self.deleted.push(path),
+        }
+    }
+
+    /*
+     * Print a count of each kind of change, then each changed path
+     */
+    pub fn print(&self) {
+        println!(
+            "{} to create, {} to update, {} to delete",
+            self.created.len(),
+            self.updated.len(),
+            self.deleted.len()
+        );
+
+        for path in self.created.iter() {
+            println!("  create  {}", path);
+        }
+        for path in self.updated.iter() {
+            println!("  update  {}", path);
+        }
+        for path in self.deleted.iter() {
+            println!("  delete  {}", path);
+        }
+    }
+}
+
+// Pull the transferred file count out of rsync's --stats output
+fn transferred_count(output: &str) -> u64 {
+    for line in output.lines() {
+        // Older versions of rsync don't say "regular"
+        let count = if line.starts_with("Number of regular files transferred:") {
+            line.splitn(2, ':').nth(1)
+        } else if line.starts_with("Number of files transferred:") {
+            line.splitn(2, ':').nth(1)
+        } else {
+            None
+        };
+
+        if let Some(count) = count {
+            return count.trim().replace(",", "").parse().unwrap_or(0);
+        }
+    }
+
+    0
+}
+
+/*
+ * Sync the remote down to the PWD, returning what changed here
+ */
+pub fn down(transport: &dyn Transport, from: PathBuf, verbosity: Verbosity) -> SlinkResult<Changes> {
+    let selection = try!(select_down(transport, &from));
+
+    let result = rsync_progress(transport, verbosity, |cmd| {
+        // the host:src string; the trailing slash syncs the directory's
+        // contents, so the filter's paths are relative to it as they are going up
+        cmd.arg(format!("{}:{}/", transport.rsync_host(), from.to_str().unwrap()));
 
         // write to the current directory
         cmd.arg(".");
+
+        // Leave out everything that's ignored on either side
+        selection.apply(cmd);
+    });
+
+    selection.remove();
+    let changes = try!(result);
+    try!(selection.finish(transport, &from, &changes.created));
+    Ok(changes)
+}
+
+/*
+ * Work out what down would change locally, without changing anything
+ */
+pub fn down_dry_run(transport: &dyn Transport, from: PathBuf) -> SlinkResult<Changes> {
+    let selection = try!(select_down(transport, &from));
+
+    let result = rsync_output(transport, |cmd| {
+        cmd.arg("--dry-run");
+        cmd.arg("--itemize-changes");
+        cmd.arg(format!("{}:{}/", transport.rsync_host(), from.to_str().unwrap()));
+        cmd.arg(".");
+        selection.apply(cmd);
+    });
+
+    selection.remove();
+    let output = try!(result);
+    Ok(Changes::parse(output.as_str()))
+}
+
+// Work out what syncing down to the PWD should fetch. Paths ignored in the
+// remote's copy aren't fetched, and paths ignored locally aren't deleted.
+fn select_down(transport: &dyn Transport, from: &PathBuf) -> SlinkResult<Selection> {
+    let pwd = paths::pwd_or_panic();
+    let settings = try!(config::project_config()).sync;
+    let mut ignores = config::ignores(&settings, Direction::Down);
+
+    // Walking first picks up the nested ignore files the remote listing is
+    // checked against
+    let protected = ignores.walk(pwd.as_path());
+    let remote_entries = try!(list(transport, from));
+    let excluded = ignores.filter(pwd.as_path(), &remote_entries);
+
+    Ok(Selection {
+        filter: write_filter(&excluded, &protected),
+        files: None,
+        backup: snapshot(&settings, Direction::Down),
     })
 }
 
-fn rsync<F>(host: &str, closure: F) -> SlinkResult<()>
+/*
+ * The snapshot for a sync in a direction to back up into, if backups are on
+ */
+pub fn snapshot(settings: &SyncConfig, direction: Direction) -> Option<Snapshot> {
+    if settings.backup() {
+        Some(Snapshot::new(direction, settings.keep_backups()))
+    } else {
+        None
+    }
+}
+
+/*
+ * Copy a single file between here and a remote, for transports that have no
+ * copy command of their own. Remote paths are given as host:path.
+ */
+pub fn copy(transport: &dyn Transport, from: &str, to: &str) -> SlinkResult<()> {
+    rsync(transport, |cmd| {
+        cmd.arg(from);
+        cmd.arg(to);
+    })
+}
+
+fn rsync<F>(transport: &dyn Transport, closure: F) -> SlinkResult<()>
     where  F: FnOnce(&mut Command) -> ()
 {
     try!(process::run("rsync", |cmd| {
-        // archive mode: preserve most things, allows modification-based optimizations
-        cmd.arg("-a");
-        cmd.arg("-v");
+        rsync_args(transport, cmd);
+        closure(cmd);
 
-        // Delete extraneous files
-        cmd.arg("--delete");
+        // Anything it prints would get mixed into the JSON
+        if output::json() {
+            cmd.stdout(Stdio::null());
+        }
+    }));
 
-        // use the persistent connection!
-        cmd.arg("-e");
-        let ssh_opts_str = conn::ssh_opts(host).join(" ");
-        cmd.arg(format!("ssh {}", ssh_opts_str));
+    Ok(())
+}
 
+// Like rsync, but following its progress and collecting what it changed
+fn rsync_progress<F>(transport: &dyn Transport, verbosity: Verbosity, closure: F) -> SlinkResult<Changes>
+    where  F: FnOnce(&mut Command) -> ()
+{
+    let show_progress = progress_supported(verbosity);
+    let mut progress = Progress::new(verbosity);
+
+    let result = process::stream("rsync", |cmd| {
+        rsync_args(transport, cmd);
+        if show_progress {
+            cmd.arg("--info=progress2");
+        }
+        cmd.arg("--itemize-changes");
+        closure(cmd);
+    }, |line| progress.line(line));
+
+    let changes = progress.finish(result.is_ok());
+    try!(result);
+    Ok(changes)
+}
+
+// --info=progress2 arrived in rsync 3.1; older ones reject it with a usage
+// message that doesn't say why, so they sync without it, after a warning
+fn progress_supported(verbosity: Verbosity) -> bool {
+    let output = match process::output("rsync", |cmd| { cmd.arg("--version"); }) {
+        Ok(output) => output,
+        // The sync itself will say what's wrong with rsync
+        Err(_) => return true,
+    };
+
+    match version(output.as_str()) {
+        Some((major, minor)) if (major, minor) < (3, 1) => {
+            if verbosity != Verbosity::Quiet && !WARNED_OLD.swap(true, Ordering::SeqCst) {
+                eprintln!(
+                    "Warning: rsync {}.{} is too old to show sync progress; that needs rsync 3.1 or newer",
+                    major, minor
+                );
+            }
+            false
+        },
+        // Versions that can't be read are given the benefit of the doubt
+        _ => true,
+    }
+}
+
+// The major and minor version from rsync --version, whose first line looks
+// like "rsync  version 3.1.2  protocol version 31" or "rsync  version v3.2.7
+// protocol version 31"
+fn version(output: &str) -> Option<(u32, u32)> {
+    let first_line = output.lines().next().unwrap_or("");
+    let mut words = first_line.split_whitespace().skip_while(|word| *word != "version");
+    let number = words.nth(1).unwrap_or("");
+
+    let mut parts = number.trim_start_matches('v').split('.');
+    match (parts.next().and_then(|major| major.parse().ok()),
+           parts.next().and_then(|minor| minor.parse().ok())) {
+        (Some(major), Some(minor)) => Some((major, minor)),
+        _ => None,
+    }
+}
+
+fn rsync_output<F>(transport: &dyn Transport, closure: F) -> SlinkResult<String>
+    where  F: FnOnce(&mut Command) -> ()
+{
+    let output = try!(process::output("rsync", |cmd| {
+        rsync_args(transport, cmd);
         closure(cmd);
     }));
 
-    Ok(())
+    Ok(output)
+}
+
+// Options common to every rsync invocation
+fn rsync_args(transport: &dyn Transport, cmd: &mut Command) {
+    // archive mode: preserve most things, allows modification-based optimizations
+    cmd.arg("-a");
+
+    // Delete extraneous files
+    cmd.arg("--delete");
+
+    // use the transport's connection!
+    cmd.arg("-e");
+    cmd.arg(transport.rsync_shell());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_listing, version, Change};
+
+    #[test]
+    fn versions() {
+        assert_eq!(version("rsync  version 3.1.2  protocol version 31\nCopyright"), Some((3, 1)));
+        assert_eq!(version("rsync  version v3.2.7  protocol version 31\n"), Some((3, 2)));
+        assert_eq!(version("rsync  version 2.6.9  protocol version 29\n"), Some((2, 6)));
+        assert_eq!(version("openrsync: protocol version 29\n"), None);
+        assert_eq!(version(""), None);
+    }
+
+    #[test]
+    fn non_ascii_lines() {
+        assert!(Change::parse("é>f+++++++ file").is_none());
+        assert!(Change::parse("Übertragen: 3 Dateien").is_none());
+        assert!(Change::parse(">f+++++++++ café").is_some());
+        assert!(parse_listing("éwxr-xr-x  4,096 2018/01/01 12:00:00 dir").is_none());
+        assert!(parse_listing("drwxr-xré  4,096 2018/01/01 12:00:00 dir").is_none());
+        assert!(parse_listing("-rw-r--r--          4,096 2018/01/01 12:00:00 café").is_some());
+    }
 }
@@ -0,0 +1,142 @@
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::time::Instant;
+use shell_escape;
+use config;
+use errors::SlinkResult;
+use output;
+use paths;
+use remote::Remotes;
+use transport::{self, ControlMaster};
+
+// Everything slink status checks, for --json output
+#[derive(Serialize)]
+struct Status {
+    remote: String,
+    description: String,
+    // The .slink/config that pins the remote, or null for the global default
+    configured_in: Option<PathBuf>,
+    // "running", "held" open by slink connect --hold, "stopped", or "unused"
+    // by the remote's transport
+    control_master: &'static str,
+    // How long the connection is configured to stay open after its last use,
+    // not how long it has left
+    #[serde(skip_serializing_if = "Option::is_none")]
+    control_persist: Option<String>,
+    reachable: bool,
+    latency_ms: Option<u64>,
+    // null if the remote couldn't be asked where its $HOME is
+    mirror: Option<PathBuf>,
+    mirror_exists: Option<bool>,
+}
+
+/*
+ * Show which remote is in use and why, and whether it can be reached
+ */
+pub fn status() -> SlinkResult<()> {
+    let remotes = try!(Remotes::load());
+    let remote = try!(config::get_remote());
+    let configured_in = try!(config::remote_config_file());
+    let transport = try!(transport::for_remote(&remotes, &remote));
+
+    // Check before anything connects, since connecting starts a master
+    let (control_master, control_persist) = match transport.control_master() {
+        ControlMaster::Unused => ("unused", None),
+        ControlMaster::Stopped => ("stopped", None),
+        ControlMaster::Running(persist) => ("running", Some(persist)),
+        ControlMaster::Held => ("held", None),
+    };
+
+    // One round trip both times the connection and checks for the mirror.
+    // It mustn't fail just because the mirror's missing, or that would look
+    // like the remote being unreachable. A mirror in the remote $HOME takes
+    // asking the remote where that is first, which fails the same way.
+    let mirror = paths::same_path(&*transport).ok();
+    let (latency_ms, mirror_exists) = match mirror {
+        Some(ref mirror) => {
+            let command = format!(
+                "if test -d {}; then echo exists; fi",
+                shell_escape::escape(Cow::Borrowed(mirror.to_str().unwrap()))
+            );
+            let start = Instant::now();
+            let result = transport.probe(command.as_str());
+            let elapsed = start.elapsed();
+
+            match result {
+                Ok(output) => {
+                    let ms = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+                    (Some(ms), Some(output.trim() == "exists"))
+                },
+                Err(_) => (None, None),
+            }
+        },
+        None => (None, None),
+    };
+
+    let status = Status {
+        remote: remote.name.clone(),
+        description: remote.to_string(),
+        configured_in: configured_in,
+        control_master: control_master,
+        control_persist: control_persist,
+        reachable: latency_ms.is_some(),
+        latency_ms: latency_ms,
+        mirror: mirror,
+        mirror_exists: mirror_exists,
+    };
+
+    if output::json() {
+        output::print_json(&status);
+    } else {
+        print(&status);
+    }
+    Ok(())
+}
+
+fn print(status: &Status) {
+    if status.remote == status.description {
+        println!("Remote:         {}", status.remote);
+    } else {
+        println!("Remote:         {} ({})", status.remote, status.description);
+    }
+
+    match status.configured_in {
+        Some(ref path) => println!("Configured in:  {}", path.display()),
+        None => println!("Configured in:  global default (slink use)"),
+    }
+
+    match status.control_persist {
+        Some(ref persist) => {
+            println!(
+                "Connection:     open; configured to close {} after its last use", persist
+            )
+        },
+        None if status.control_master == "held" => {
+            println!("Connection:     open until slink disconnect")
+        },
+        None if status.control_master == "stopped" => println!("Connection:     not open"),
+        None => println!("Connection:     not shared by this remote"),
+    }
+
+    match status.latency_ms {
+        // Without an open connection, the time includes connecting
+        Some(ms) if status.control_master == "stopped" => {
+            println!("Reachable:      yes, {}ms including connecting", ms)
+        },
+        Some(ms) => println!("Reachable:      yes, {}ms round trip", ms),
+        None => println!("Reachable:      no"),
+    }
+
+    let mirror = match status.mirror {
+        Some(ref mirror) => mirror,
+        None => {
+            println!("Mirror:         unknown, since the remote $HOME couldn't be found");
+            return;
+        },
+    };
+    match status.mirror_exists {
+        Some(true) => println!("Mirror:         {}", mirror.display()),
+        Some(false) => println!("Mirror:         {} (doesn't exist yet)", mirror.display()),
+        None => println!("Mirror:         {} (couldn't check)", mirror.display()),
+    }
+}
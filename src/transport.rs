@@ -0,0 +1,212 @@
+use std::path::Path;
+use std::io;
+use std::process::Child;
+use errors::{SlinkError, SlinkResult};
+use config::{self, CommandKind};
+use conn::Ssh;
+use exec;
+use forward::Forward;
+use kubectl::Kubectl;
+use process;
+use remote::{Remote, RemoteKind, Remotes};
+
+pub enum Error {
+    Unsupported(String, &'static str),
+    NoMatchingPod(String),
+    ViaCycle(String),
+    // Marking the shared connection as held open failed
+    FailedHold(io::Error),
+    // The command the user asked the remote to run exited with this code
+    RemoteExit(i32),
+}
+
+/*
+ * The state of a transport's shared connection
+ */
+pub enum ControlMaster {
+    // The transport doesn't keep one
+    Unused,
+    Stopped,
+    // Running, and kept open this long after its last use
+    Running(String),
+    // Running until slink disconnect
+    Held,
+}
+
+/*
+ * A way of running commands on and moving files to and from a remote.
+ */
+pub trait Transport {
+    /*
+     * The name of the remote this transport connects to
+     */
+    fn name(&self) -> &str;
+
+    /*
+     * Run a shell command on the remote. If tty is set, a PTY is allocated when
+     * stdout is a tty; otherwise stdio is passed straight through, so the
+     * command can be used as a pipe.
+     */
+    fn command(&self, command: &str, tty: bool) -> SlinkResult<()>;
+
+    /*
+     * Run a shell command on the remote and capture its stdout
+     */
+    fn output(&self, command: &str) -> SlinkResult<String>;
+
+    /*
+     * Like output, but give up soon if the remote can't be reached, rather
+     * than waiting as long as connecting can take
+     */
+    fn probe(&self, command: &str) -> SlinkResult<String> {
+        self.output(command)
+    }
+
+    fn upload(&self, from: &Path, to: &Path) -> SlinkResult<()>;
+
+    fn download(&self, from: &Path, to: &Path) -> SlinkResult<()>;
+
+    /*
+     * The remote shell rsync should use, as passed to its -e flag
+     */
+    fn rsync_shell(&self) -> String;
+
+    /*
+     * What goes before the colon in rsync's host:path arguments
+     */
+    fn rsync_host(&self) -> String;
+
+    /*
+     * Hold any shared connection open until the returned child is killed
+     */
+    fn keep_alive(&self) -> SlinkResult<Option<Child>> {
+        Ok(None)
+    }
+
+    /*
+     * Check on the shared connection, without starting one
+     */
+    fn control_master(&self) -> ControlMaster {
+        ControlMaster::Unused
+    }
+
+    /*
+     * Open the shared connection ahead of time. If hold is set, it stays open
+     * until disconnected instead of timing out.
+     */
+    fn connect(&self, _hold: bool) -> SlinkResult<()> {
+        Err(From::from(unsupported(self.name(), "shared connections")))
+    }
+
+    /*
+     * Close the shared connection, if it's open
+     */
+    fn disconnect(&self) -> SlinkResult<()> {
+        Err(From::from(unsupported(self.name(), "shared connections")))
+    }
+
+    /*
+     * Connect stdio to a TCP port as seen from the remote, for use as another
+     * connection's proxy. Relies on nc being installed on the remote unless
+     * the transport has something better.
+     */
+    fn proxy(&self, host: &str, port: u16) -> SlinkResult<()> {
+        let args = vec![host.to_string(), port.to_string()];
+        self.command(exec::shell_join("nc", &args).as_str(), false)
+    }
+
+    /*
+     * Forward ports until interrupted
+     */
+    fn port_forward(&self, _forwards: &[Forward]) -> SlinkResult<()> {
+        Err(From::from(unsupported(self.name(), "port forwarding")))
+    }
+
+    /*
+     * Add forwards to the shared connection, which must already be open. They
+     * run in the background until cancelled or the connection closes.
+     */
+    fn add_forwards(&self, _forwards: &[Forward]) -> SlinkResult<()> {
+        Err(From::from(unsupported(self.name(), "background port forwarding")))
+    }
+
+    fn cancel_forwards(&self, _forwards: &[Forward]) -> SlinkResult<()> {
+        Err(From::from(unsupported(self.name(), "background port forwarding")))
+    }
+}
+
+/*
+ * Build the transport for a remote, along with the transports for any remotes
+ * it's reached through.
+ */
+pub fn for_remote(remotes: &Remotes, remote: &Remote) -> SlinkResult<Box<dyn Transport>> {
+    build(remotes, remote, &mut Vec::new())
+}
+
+/*
+ * The transport for the remote a command should use.
+ */
+pub fn for_command(command: CommandKind) -> SlinkResult<Box<dyn Transport>> {
+    let remotes = try!(Remotes::load());
+    let remote = try!(config::get_remote_for(command));
+    for_remote(&remotes, &remote)
+}
+
+// The chain of remote names traversed so far is tracked so that a remote
+// that's (eventually) reached via itself is an error, rather than infinite
+// recursion
+fn build(remotes: &Remotes, remote: &Remote, chain: &mut Vec<String>)
+    -> SlinkResult<Box<dyn Transport>>
+{
+    chain.push(remote.name.clone());
+    if chain[..chain.len() - 1].contains(&remote.name) {
+        return Err(From::from(Error::ViaCycle(chain.join(" -> "))));
+    }
+
+    match remote.kind {
+        RemoteKind::Ssh(ref ssh) => {
+            Ok(Box::new(Ssh::new(remote.name.as_str(), ssh, None)))
+        },
+
+        RemoteKind::Kubectl(ref kubectl) => {
+            let via = match kubectl.via {
+                Some(ref via) => {
+                    let via_remote = try!(remotes.get(via.as_str()));
+                    Some(try!(build(remotes, &via_remote, chain)))
+                },
+                None => None,
+            };
+            Ok(Box::new(Kubectl::new(remote.name.as_str(), kubectl, via)))
+        },
+
+        RemoteKind::Forward(ref forward) => {
+            // The via remote is only used by the `slink proxy` subprocess, but
+            // check it now so that a bad chain fails here rather than in ssh
+            let via_remote = try!(remotes.get(forward.via.as_str()));
+            try!(build(remotes, &via_remote, chain));
+
+            Ok(Box::new(Ssh::new(
+                remote.name.as_str(),
+                &forward.ssh_remote(),
+                Some(forward.via.clone())
+            )))
+        },
+    }
+}
+
+/*
+ * For commands that stand in for a remote command: a nonzero exit from running
+ * it is the remote command's own, rather than a failure of slink's
+ */
+pub fn remote_exit(result: SlinkResult<()>) -> SlinkResult<()> {
+    match result {
+        Err(SlinkError::ProcessError(process::Error::NonZeroExit(_, code))) => {
+            Err(From::from(Error::RemoteExit(code)))
+        },
+        result => result,
+    }
+}
+
+pub fn unsupported(name: &str, operation: &'static str) -> Error {
+    Error::Unsupported(name.to_string(), operation)
+}
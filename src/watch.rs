@@ -0,0 +1,166 @@
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+use chrono::Local;
+use isatty;
+use notify::{self, DebouncedEvent, RecursiveMode, Watcher};
+use config;
+use errors::SlinkResult;
+use ignore::{Direction, Ignores};
+use output;
+use paths;
+use rsync;
+use transport::Transport;
+
+// How long the filesystem has to be quiet before a burst of changes is synced
+const DEBOUNCE_MS: u64 = 300;
+
+/*
+ * Sync the PWD up to the remote, then keep watching it and sync changed paths
+ * as they happen. Runs until interrupted.
+ */
+pub fn up(transport: &dyn Transport, to: PathBuf) -> SlinkResult<()> {
+    let pwd = paths::pwd_or_panic();
+
+    // Hold the shared connection open for as long as we're watching, so that
+    // each sync doesn't pay for a new handshake once ControlPersist expires
+    let keep_alive = try!(transport.keep_alive());
+
+    let result = watch(transport, to, pwd);
+
+    if let Some(mut child) = keep_alive {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    result
+}
+
+fn watch(transport: &dyn Transport, to: PathBuf, pwd: PathBuf) -> SlinkResult<()> {
+    let (tx, rx) = channel();
+    let mut watcher = try!(notify::watcher(tx, Duration::from_millis(DEBOUNCE_MS)));
+    try!(watcher.watch(&pwd, RecursiveMode::Recursive));
+
+    let count = try!(rsync::up_counted(transport, to.clone()));
+    status(count);
+
+    let mut ignores = try!(load_ignores(&pwd));
+
+    loop {
+        let event = match rx.recv() {
+            Ok(event) => event,
+            // The watcher hung up, so there's nothing more to wait for
+            Err(_) => return Ok(()),
+        };
+
+        let mut changed = BTreeSet::new();
+        let mut rescan = collect(event, &pwd, &mut changed);
+
+        // Pick up anything else that happened while we were busy
+        while let Ok(event) = rx.try_recv() {
+            rescan = collect(event, &pwd, &mut changed) || rescan;
+        }
+
+        // Changing the rules can change what should be on the remote anywhere
+        // in the tree
+        let rules_changed = changed.iter().any(|path| {
+            path.ends_with(".slink/ignore") || path.ends_with(".slink/config") ||
+                path.ends_with(".gitignore") || path.ends_with(".git/info/exclude")
+        });
+        if rules_changed {
+            ignores = try!(load_ignores(&pwd));
+        }
+
+        // Don't bother rsync with changes it would skip anyway, like build
+        // output
+        let changed: BTreeSet<PathBuf> = changed.into_iter().filter(|path| {
+            !ignores.is_ignored(&pwd, path, pwd.join(path).is_dir())
+        }).collect();
+
+        let count = if rescan || rules_changed {
+            try!(rsync::up_counted(transport, to.clone()))
+        } else if changed.is_empty() {
+            continue;
+        } else {
+            try!(rsync::up_paths(transport, to.clone(), &changed))
+        };
+        status(count);
+    }
+}
+
+// Add the paths touched by an event to the changed set. Returns true if the
+// event means the whole tree needs to be resynced.
+fn collect(event: DebouncedEvent, pwd: &PathBuf, changed: &mut BTreeSet<PathBuf>) -> bool {
+    match event {
+        DebouncedEvent::Create(path) |
+        DebouncedEvent::Write(path) |
+        DebouncedEvent::Chmod(path) |
+        DebouncedEvent::Remove(path) => {
+            add_relative(path, pwd, changed);
+            false
+        },
+
+        DebouncedEvent::Rename(from, to) => {
+            add_relative(from, pwd, changed);
+            add_relative(to, pwd, changed);
+            false
+        },
+
+        // Notices are always followed by the real event
+        DebouncedEvent::NoticeWrite(_) |
+        DebouncedEvent::NoticeRemove(_) => false,
+
+        // The watcher lost track of what happened, so play it safe
+        DebouncedEvent::Rescan |
+        DebouncedEvent::Error(_, _) => true,
+    }
+}
+
+// The ignore patterns for the tree, including ones nested inside it
+fn load_ignores(pwd: &PathBuf) -> SlinkResult<Ignores> {
+    let settings = try!(config::project_config()).sync;
+    let mut ignores = config::ignores(&settings, Direction::Up);
+    ignores.walk(pwd);
+    Ok(ignores)
+}
+
+fn add_relative(path: PathBuf, pwd: &PathBuf, changed: &mut BTreeSet<PathBuf>) {
+    if let Ok(rel_path) = path.strip_prefix(pwd) {
+        if rel_path.as_os_str().len() > 0 {
+            changed.insert(rel_path.to_path_buf());
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Synced {
+    synced_at: String,
+    transferred: u64,
+}
+
+// Overwrite the status line in place on a tty, or log a new line otherwise
+fn status(count: u64) {
+    if output::json() {
+        output::print_json(&Synced {
+            synced_at: Local::now().to_rfc3339(),
+            transferred: count,
+        });
+        return;
+    }
+
+    let message = format!(
+        "Last synced at {}: {} file{} transferred",
+        Local::now().format("%H:%M:%S"),
+        count,
+        if count == 1 { "" } else { "s" }
+    );
+
+    if isatty::stdout_isatty() {
+        print!("\r\x1B[2K{} (watching for changes)", message);
+        let _ = io::stdout().flush();
+    } else {
+        println!("{}", message);
+    }
+}